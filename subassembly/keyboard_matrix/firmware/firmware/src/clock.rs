@@ -0,0 +1,75 @@
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m::peripheral::SYST;
+
+use crate::kib_board as bsp;
+
+use bsp::hal::ehal::blocking::delay::DelayUs;
+
+const RELOAD: u32 = 0x00FF_FFFF;
+
+/// Free running millisecond clock counted off SysTick.  Also provides the microsecond delays for the matrix
+/// scan, which would otherwise need SysTick to themselves.
+///
+/// SysTick wraps every 2^24 core clocks, about 350ms at 48MHz.  `now_ms` or `delay_us` must be called more
+/// often than that or the time in between is lost.
+pub struct SysTickClock {
+    // Held so nothing else can reprogram the counter
+    _syst: SYST,
+    ticks_per_ms: u32,
+    ticks_per_us: u32,
+    // SysTick counts down, the value it held when last read
+    last_tick: u32,
+    // Ticks seen but not yet folded into `now_ms`
+    pending_ticks: u32,
+    now_ms: u32,
+}
+
+impl SysTickClock {
+    pub fn new(mut syst: SYST, core_hz: u32) -> Self {
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(RELOAD);
+        syst.clear_current();
+        syst.enable_counter();
+
+        Self {
+            _syst: syst,
+            ticks_per_ms: core_hz / 1_000,
+            ticks_per_us: (core_hz / 1_000_000).max(1),
+            last_tick: SYST::get_current(),
+            pending_ticks: 0,
+            now_ms: 0,
+        }
+    }
+
+    /// Milliseconds since the clock started, wrapping at `u32::MAX`.
+    pub fn now_ms(&mut self) -> u32 {
+        self.tick();
+
+        self.now_ms = self.now_ms.wrapping_add(self.pending_ticks / self.ticks_per_ms);
+        self.pending_ticks %= self.ticks_per_ms;
+
+        self.now_ms
+    }
+
+    // Adds the ticks since the last read to `pending_ticks`, returns how many there were
+    fn tick(&mut self) -> u32 {
+        let current = SYST::get_current();
+        let elapsed = self.last_tick.wrapping_sub(current) & RELOAD;
+
+        self.last_tick = current;
+        self.pending_ticks += elapsed;
+
+        elapsed
+    }
+}
+
+impl DelayUs<u16> for SysTickClock {
+    fn delay_us(&mut self, us: u16) {
+        let wait = us as u32 * self.ticks_per_us;
+        let mut waited = 0;
+
+        while waited < wait {
+            waited += self.tick();
+        }
+    }
+}
@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 
+mod clock;
 mod i2c_peripheral;
 mod kib_board;
 mod protocol;
@@ -24,7 +25,7 @@ use pac::interrupt;
 use pac::{CorePeripherals, Peripherals};
 
 use hal::clock::GenericClockController;
-use hal::delay::Delay;
+use hal::gpio::DynPin;
 use hal::prelude::*;
 use hal::sercom::i2c;
 use hal::sercom::Sercom;
@@ -33,11 +34,20 @@ use hal::timer::*;
 
 use ws2812_timer_delay as ws2812;
 
-use keyboard_matrix::KeyboardMatrix;
+use keyboard_matrix::{
+    EagerDebouncer, IdlePolicy, KeyboardMatrix, KeyboardState, ScanMode, KIB_COLS, KIB_LAYOUT, KIB_ROWS,
+};
 use synth_engine::SynthEngine;
 
 use illuminator::IlluminationEngine;
 
+use clock::SysTickClock;
+
+const DEBOUNCE_MS: u32 = 20;
+const STUCK_KEY_MS: u32 = 30_000;
+const IDLE_AFTER_MS: u32 = 10_000;
+const IDLE_POLL_MS: u32 = 20;
+
 #[entry]
 fn main() -> ! {
     //Initial hardware configuration
@@ -74,22 +84,33 @@ fn main() -> ! {
         NVIC::unmask(interrupt::SERCOM0);
     }
 
-    let mut delay = Delay::new(core.SYST, &mut clocks);
+    let mut clock = SysTickClock::new(core.SYST, gclk0.freq().to_Hz());
 
     let mut synth_engine = SynthEngine::new();
 
-    let mut keyboard_matrix = KeyboardMatrix::new(
-        pins.row_a.into_push_pull_output(),
-        pins.row_b.into_push_pull_output(),
-        pins.row_c.into_push_pull_output(),
-        pins.row_d.into_push_pull_output(),
-        pins.row_e.into_push_pull_output(),
-        pins.col_n.into_pull_down_input(),
-        pins.col_m.into_pull_down_input(),
-        pins.col_o.into_pull_down_input(),
-        pins.col_p.into_pull_down_input(),
-        pins.col_q.into_pull_down_input(),
-    );
+    let rows: [DynPin; KIB_ROWS] = [
+        pins.row_a.into_push_pull_output().into(),
+        pins.row_b.into_push_pull_output().into(),
+        pins.row_c.into_push_pull_output().into(),
+        pins.row_d.into_push_pull_output().into(),
+        pins.row_e.into_push_pull_output().into(),
+    ];
+
+    let cols: [DynPin; KIB_COLS] = [
+        pins.col_m.into_pull_down_input().into(),
+        pins.col_n.into_pull_down_input().into(),
+        pins.col_o.into_pull_down_input().into(),
+        pins.col_p.into_pull_down_input().into(),
+        pins.col_q.into_pull_down_input().into(),
+    ];
+
+    let mut keyboard_matrix =
+        KeyboardMatrix::new(rows, cols, &KIB_LAYOUT, EagerDebouncer::new(DEBOUNCE_MS));
+    keyboard_matrix.set_stuck_key_limit(STUCK_KEY_MS, true);
+
+    let mut self_test_report = keyboard_matrix.self_test(&mut clock).ok();
+
+    let mut idle_policy = IdlePolicy::new(IDLE_AFTER_MS, IDLE_POLL_MS);
 
     let mut led_timer = TimerCounter::tc1_(tc12, peripherals.TC1, &mut peripherals.PM);
     led_timer.start(MegaHertz::MHz(7).into_duration());
@@ -101,10 +122,12 @@ fn main() -> ! {
 
     let mut illumination_engine = IlluminationEngine::new(&mut led_strand);
 
-    let delta_t_ms = 2;
+    let mut last_update_ms = clock.now_ms();
 
     let mut communication_register: u8 = 0x00;
 
+    let mut keystate = KeyboardState::default();
+
     loop {
         //Process protocol commands
         let command = interrupt_helpers::free(|cs| {
@@ -119,13 +142,43 @@ fn main() -> ! {
         if let Some(command) = command {
             communication_register = command.register;
 
+            // Any write to the self test register reruns it
+            if command.register == 0x31 && command.data_size > 0 {
+                self_test_report = keyboard_matrix.self_test(&mut clock).ok();
+            }
+
             protocol::process_command(&command, &mut synth_engine, &mut illumination_engine);
         }
 
-        let keystate = keyboard_matrix.scan(&mut delay);
+        let now_ms = clock.now_ms();
+
+        keystate = match idle_policy.mode() {
+            ScanMode::Active => match keyboard_matrix.scan(&mut clock, now_ms) {
+                Ok(keystate) => {
+                    idle_policy.scanned(&keystate, now_ms);
+                    keystate
+                }
+                // Abandoned scans are counted in the diagnostics, hold the last state until the next scan
+                Err(_) => keystate.build_new(keystate.state),
+            },
+            ScanMode::Idle => {
+                if idle_policy.poll_due(now_ms) {
+                    if let Ok(any_closed) = keyboard_matrix.any_key_closed(&mut clock) {
+                        idle_policy.polled(any_closed, now_ms);
+                    }
+                }
+
+                // Nothing has been held since going idle, a closed key is picked up by the next full scan
+                KeyboardState::default()
+            }
+        };
+
+        // Time since the engines last ran, loop iterations take as long as the scan and render do
+        let delta_t_ms = now_ms.wrapping_sub(last_update_ms);
+        last_update_ms = now_ms;
 
         // Update Synth Engine state
-        synth_engine.update(&keystate);
+        synth_engine.update(delta_t_ms, &keystate);
 
         illumination_engine.update(delta_t_ms, &keystate, &synth_engine.state);
 
@@ -134,7 +187,7 @@ fn main() -> ! {
         //Update protocol response
         //Note that this should be imdepotent.  If not, should check `can_provide_data` first.
         if let Some((register_data, data_size)) =
-            protocol::build_response(communication_register, &synth_engine, &illumination_engine)
+            protocol::build_response(communication_register, &synth_engine, &illumination_engine, keyboard_matrix.diagnostics(), &self_test_report)
         {
             interrupt_helpers::free(|cs| {
                 if let Some(comms_status) =
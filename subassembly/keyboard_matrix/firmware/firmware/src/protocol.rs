@@ -1,9 +1,11 @@
-use synth_engine::SynthEngine;
+use synth_engine::{ArpOrder, ChordShape, LooperState, NotePriority, Scale, StealPolicy, SynthEngine};
 
 use illuminator::IlluminationEngine;
 
 use comms::BusCommand;
 
+use keyboard_matrix::{Diagnostics, SelfTestReport};
+
 use smart_leds::SmartLedsWrite;
 use smart_leds::RGB8;
 
@@ -15,12 +17,143 @@ where LedStrand: SmartLedsWrite<Error = (), Color = RGB8> {
                 synth_engine.set_octave(command.data[0])
             }
         }
+        0x21 => {
+            if command.data_size == 1 {
+                synth_engine.state.keymap.set_active_layers(command.data[0])
+            }
+        }
+        0x22 => {
+            if command.data_size == 1 {
+                synth_engine.set_transpose(command.data[0] as i8)
+            }
+        }
+        0x23 => {
+            // Scale preset, or 4 followed by the semitone offset of each degree
+            let scale = match command.data[..command.data_size.min(command.data.len())] {
+                [0] => Some(Scale::CHROMATIC),
+                [1] => Some(Scale::MAJOR),
+                [2] => Some(Scale::MINOR),
+                [3] => Some(Scale::PENTATONIC),
+                [4, ref degrees @ ..] => Scale::custom(degrees),
+                _ => None,
+            };
+
+            if let Some(scale) = scale {
+                synth_engine.set_scale(scale)
+            }
+        }
+        0x24 => {
+            // Sustain pedal with CC64 semantics, down for 64 and above
+            if command.data_size == 1 {
+                synth_engine.set_sustain_pedal(command.data[0] >= 64)
+            }
+        }
+        0x25 => {
+            if command.data_size == 1 {
+                synth_engine.set_latch(command.data[0] != 0)
+            }
+        }
+        0x26 => {
+            // Arpeggiator enabled, order, BPM (2 bytes LE), steps per beat, gate percent, octaves
+            if command.data_size == 7 {
+                if let Some(order) = ArpOrder::from_int(command.data[1]) {
+                    let arpeggiator = &mut synth_engine.state.arpeggiator;
+
+                    arpeggiator.enabled = command.data[0] != 0;
+                    arpeggiator.order = order;
+                    arpeggiator.bpm = u16::from_le_bytes([command.data[2], command.data[3]]).max(1);
+                    arpeggiator.subdivision = command.data[4].max(1);
+                    arpeggiator.gate_percent = command.data[5].clamp(1, 100);
+                    arpeggiator.octaves = command.data[6].clamp(1, 4);
+                }
+            }
+        }
+        0x27 => {
+            // Diatonic flag then the chord: 0 off, a preset, or 5 followed by the semitone offset of each note
+            if let [diatonic, ref chord @ ..] = command.data[..command.data_size.min(command.data.len())] {
+                let chord = match chord {
+                    [0] => Some(None),
+                    [1] => Some(Some(ChordShape::MAJOR)),
+                    [2] => Some(Some(ChordShape::MINOR)),
+                    [3] => Some(Some(ChordShape::SEVENTH)),
+                    [4] => Some(Some(ChordShape::POWER)),
+                    [5, semitones @ ..] => ChordShape::custom(semitones).map(Some),
+                    _ => None,
+                };
+
+                if let Some(chord) = chord {
+                    synth_engine.set_chord(chord);
+                    synth_engine.set_chord_diatonic(diatonic != 0);
+                }
+            }
+        }
+        0x28 => {
+            // Voice allocation enabled, voice count, steal policy
+            if command.data_size == 3 {
+                if let Some(policy) = StealPolicy::from_int(command.data[2]) {
+                    let voices = &mut synth_engine.state.voices;
+
+                    voices.enabled = command.data[0] != 0;
+                    voices.set_voice_count(command.data[1] as usize);
+                    voices.policy = policy;
+                }
+            }
+        }
+        0x29 => {
+            // Mono enabled, note priority, legato, portamento time in ms (2 bytes LE)
+            if command.data_size == 5 {
+                if let Some(priority) = NotePriority::from_int(command.data[1]) {
+                    let mono = &mut synth_engine.state.mono;
+
+                    mono.enabled = command.data[0] != 0;
+                    mono.priority = priority;
+                    mono.legato = command.data[2] != 0;
+                    mono.portamento_ms = u16::from_le_bytes([command.data[3], command.data[4]]);
+                }
+            }
+        }
+        0x2A => {
+            // Note attack then release time in ms (2 bytes LE each)
+            if command.data_size == 4 {
+                synth_engine.state.attack_ms = u16::from_le_bytes([command.data[0], command.data[1]]);
+                synth_engine.state.release_ms = u16::from_le_bytes([command.data[2], command.data[3]]);
+            }
+        }
+        0x2B => {
+            // Loop length in bars, tempo in BPM (2 bytes LE), grid steps per beat
+            if command.data_size == 4 {
+                let looper = &mut synth_engine.state.looper;
+
+                // Regrid first so the length change trims events on the new grid
+                looper.set_grid(u16::from_le_bytes([command.data[1], command.data[2]]), command.data[3]);
+                looper.set_bars(command.data[0]);
+            }
+        }
+        0x2C => {
+            // Loop transport: a LooperState to move to, or 4 to clear
+            if command.data_size == 1 {
+                let looper = &mut synth_engine.state.looper;
+
+                match LooperState::from_int(command.data[0]) {
+                    Some(LooperState::Stopped) => looper.stop(),
+                    Some(LooperState::Recording) => looper.record(),
+                    Some(LooperState::Playing) => looper.play(),
+                    Some(LooperState::Overdubbing) => looper.overdub(),
+                    None if command.data[0] == 4 => looper.clear(),
+                    None => {}
+                }
+            }
+        }
+        0x40 => {
+            // MIDI input byte stream, messages may be split across writes
+            synth_engine.receive_midi_bytes(&command.data[..command.data_size.min(command.data.len())])
+        }
 
         _ => { }
     }
 }
 
-pub fn build_response<LedStrand>(register: u8, synth_engine: &SynthEngine, illumination_engine: &IlluminationEngine<LedStrand>) -> Option<([u8; 20], usize)>
+pub fn build_response<LedStrand>(register: u8, synth_engine: &SynthEngine, illumination_engine: &IlluminationEngine<LedStrand>, diagnostics: &Diagnostics, self_test_report: &Option<SelfTestReport>) -> Option<([u8; 20], usize)>
 where LedStrand: SmartLedsWrite<Error = (), Color = RGB8> {
     
     let mut register_data: [u8; 20] = [0; 20];
@@ -41,6 +174,135 @@ where LedStrand: SmartLedsWrite<Error = (), Color = RGB8> {
 
             Some((register_data, 1))
         }
+        0x21 => {
+            // Active keymap layers, layer 0 in the lowest bit
+            register_data[0] = synth_engine.state.keymap.active_layers();
+
+            Some((register_data, 1))
+        }
+        0x22 => {
+            register_data[0] = synth_engine.state.transpose as u8;
+
+            Some((register_data, 1))
+        }
+        0x23 => {
+            // Degree count then the semitone offset of each degree
+            let degrees = synth_engine.state.scale.degrees();
+
+            register_data[0] = degrees.len() as u8;
+            register_data[1..=degrees.len()].copy_from_slice(degrees);
+
+            Some((register_data, degrees.len() + 1))
+        }
+        0x24 => {
+            register_data[0] = if synth_engine.state.sustain_pedal { 127 } else { 0 };
+
+            Some((register_data, 1))
+        }
+        0x25 => {
+            register_data[0] = synth_engine.state.latch as u8;
+
+            Some((register_data, 1))
+        }
+        0x26 => {
+            let arpeggiator = &synth_engine.state.arpeggiator;
+
+            register_data[0] = arpeggiator.enabled as u8;
+            register_data[1] = arpeggiator.order.to_int();
+            register_data[2..4].copy_from_slice(&arpeggiator.bpm.to_le_bytes());
+            register_data[4] = arpeggiator.subdivision;
+            register_data[5] = arpeggiator.gate_percent;
+            register_data[6] = arpeggiator.octaves;
+
+            Some((register_data, 7))
+        }
+        0x27 => {
+            // Diatonic flag, note count (0 when off) then the semitone offset of each note
+            let semitones = synth_engine.state.chord.as_ref().map_or(&[][..], |chord| chord.semitones());
+
+            register_data[0] = synth_engine.state.chord_diatonic as u8;
+            register_data[1] = semitones.len() as u8;
+            register_data[2..2 + semitones.len()].copy_from_slice(semitones);
+
+            Some((register_data, 2 + semitones.len()))
+        }
+        0x28 => {
+            let voices = &synth_engine.state.voices;
+
+            register_data[0] = voices.enabled as u8;
+            register_data[1] = voices.voice_count() as u8;
+            register_data[2] = voices.policy.to_int();
+
+            Some((register_data, 3))
+        }
+        0x29 => {
+            let mono = &synth_engine.state.mono;
+
+            register_data[0] = mono.enabled as u8;
+            register_data[1] = mono.priority.to_int();
+            register_data[2] = mono.legato as u8;
+            register_data[3..5].copy_from_slice(&mono.portamento_ms.to_le_bytes());
+
+            Some((register_data, 5))
+        }
+        0x2A => {
+            register_data[0..2].copy_from_slice(&synth_engine.state.attack_ms.to_le_bytes());
+            register_data[2..4].copy_from_slice(&synth_engine.state.release_ms.to_le_bytes());
+
+            Some((register_data, 4))
+        }
+        0x2B => {
+            let looper = &synth_engine.state.looper;
+
+            register_data[0] = looper.bars();
+            register_data[1..3].copy_from_slice(&looper.bpm().to_le_bytes());
+            register_data[3] = looper.subdivision();
+
+            Some((register_data, 4))
+        }
+        0x2C => {
+            // Loop state then recorded event count
+            let looper = &synth_engine.state.looper;
+
+            register_data[0] = looper.state().to_int();
+            register_data[1] = looper.event_count() as u8;
+
+            Some((register_data, 2))
+        }
+        0x30 => {
+            // Stuck key bitmask, then the key with the most rejected transitions and its bounce and
+            // rejection counts.  Key 255 when no transitions have been rejected.  Followed by the number of
+            // abandoned scans.
+            register_data[0..4].copy_from_slice(&diagnostics.stuck_keys().bits().to_le_bytes());
+
+            match diagnostics.noisiest_key() {
+                Some((key, key_diagnostics)) => {
+                    register_data[4] = key as u8;
+                    register_data[5..7].copy_from_slice(&key_diagnostics.bounces().to_le_bytes());
+                    register_data[7..9].copy_from_slice(&key_diagnostics.rejected.to_le_bytes());
+                }
+                None => register_data[4] = 255,
+            }
+
+            register_data[9..11].copy_from_slice(&diagnostics.scan_errors.to_le_bytes());
+
+            Some((register_data, 11))
+        }
+        0x31 => {
+            // 1 if the last self test passed, 0 if it found faults, 255 if it could not run.  Followed by the
+            // shorted row, stuck column and closed key bitmasks.
+            match self_test_report {
+                Some(report) => {
+                    register_data[0] = report.passed() as u8;
+                    register_data[1..5].copy_from_slice(&report.shorted_drive.to_le_bytes());
+                    register_data[5..9].copy_from_slice(&report.stuck_sense.to_le_bytes());
+                    register_data[9..13].copy_from_slice(&report.closed_keys.bits().to_le_bytes());
+                }
+                None => register_data[0] = 255,
+            }
+
+            Some((register_data, 13))
+        }
         _ => { 
             None
         }
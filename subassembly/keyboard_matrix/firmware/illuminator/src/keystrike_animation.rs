@@ -128,18 +128,31 @@ impl PixelAnimation for SelectedOctaveAnimation {
 pub struct KeyFadeAnimation {}
 
 impl KeyFadeAnimation {
-    pub fn is_complete(duration: u32) -> bool {
-        duration > FADE_DURATION
+    /// Fade time for a released key, the synth's note release time or the default fade when that is 0.
+    pub fn fade_ms(release_ms: u16) -> u16 {
+        match release_ms {
+            0 => FADE_DURATION as u16,
+            release_ms => release_ms,
+        }
     }
-}
 
-impl PixelAnimation for KeyFadeAnimation {
-    fn compute(data: u32, duration: u32) -> RGB8 {
-        let percent = min(100, (duration / 10) as u8);
+    /// Color `duration` ms into a fade lasting `fade_ms`.
+    pub fn compute_over(data: u32, duration: u32, fade_ms: u16) -> RGB8 {
+        let percent = (duration as u64 * 100 / fade_ms.max(1) as u64).min(100) as u8;
         let original_color = RGB8::deserialize(data);
 
         original_color.fade(RGB8::default(), percent)
     }
+
+    pub fn is_complete(duration: u32, fade_ms: u16) -> bool {
+        duration > fade_ms as u32
+    }
+}
+
+impl PixelAnimation for KeyFadeAnimation {
+    fn compute(data: u32, duration: u32) -> RGB8 {
+        Self::compute_over(data, duration, FADE_DURATION as u16)
+    }
 }
 
 pub struct KeyRadiantAnimation {}
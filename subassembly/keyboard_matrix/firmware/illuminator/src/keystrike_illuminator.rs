@@ -4,7 +4,7 @@ use crate::data::*;
 
 use crate::keystrike_animation::*;
 
-use keyboard_matrix::KeyboardState;
+use keyboard_matrix::{KeyAction, KeyboardState, Keymap};
 use synth_engine::SynthState;
 
 use smart_leds::hsv::RGB8;
@@ -31,6 +31,8 @@ struct KeyData {
     state: KeyState,
     data: u32,
     counter: u32,
+    // Length of the current fade
+    fade_ms: u16,
 }
 
 impl KeyData {
@@ -39,35 +41,33 @@ impl KeyData {
             state: KeyState::Off,
             data: 0,
             counter: 0,
+            fade_ms: 0,
         }
     }
 }
 
 pub struct KeystrikeIlluminator {
     key_data: [KeyData; 21],
+    key_types: [KeyType; 21],
 }
 
 impl KeystrikeIlluminator {
     pub fn new() -> Self {
         Self {
             key_data: [KeyData::new(); 21],
+            key_types: [KeyType::Normal; 21],
         }
     }
 }
 
 impl KeystrikeIlluminator {
-    fn keytype_for_index(key_index: usize) -> KeyType {
-        match key_index {
-            0..=7 => KeyType::Octave,
+    fn keytype_for_index(keymap: &Keymap, key_index: usize) -> KeyType {
+        match keymap.action(key_index) {
+            KeyAction::Octave(_) => KeyType::Octave,
             _ => KeyType::Normal,
         }
     }
 
-    fn compute_pixel_for_index(key_index: usize, key_data: &KeyData) -> Option<RGB8> {
-        let key_type = KeystrikeIlluminator::keytype_for_index(key_index);
-        KeystrikeIlluminator::compute_pixel(key_type, key_data)
-    }
-
     fn compute_pixel(key_type: KeyType, key_data: &KeyData) -> Option<RGB8> {
         let color: Option<RGB8> = match key_data.state {
             KeyState::Pressed => match key_type {
@@ -80,7 +80,11 @@ impl KeystrikeIlluminator {
                     key_data.counter,
                 )),
             },
-            KeyState::Fade => Some(KeyFadeAnimation::compute(key_data.data, key_data.counter)),
+            KeyState::Fade => Some(KeyFadeAnimation::compute_over(
+                key_data.data,
+                key_data.counter,
+                key_data.fade_ms,
+            )),
             KeyState::Radiant => Some(KeyRadiantAnimation::compute(
                 key_data.data,
                 key_data.counter,
@@ -103,15 +107,37 @@ impl Illuminator for KeystrikeIlluminator {
         keyboard_state: &KeyboardState,
         synth_state: &SynthState,
     ) {
+        let selected_octave = KeyAction::Octave(synth_state.octave);
+
+        // Notes from MIDI input and the looper light up like local presses, with the arpeggiator only its
+        // current note lights as it also plays the loop
+        let played_keys = if synth_state.arpeggiator.enabled {
+            synth_state.arpeggiated_keys()
+        } else {
+            keyboard_state.state | synth_state.looped_keys()
+        };
+        let held_keys = played_keys | synth_state.external_keys();
+
         //Set selected octave
-        self.key_data[synth_state.octave as usize - 1].state = KeyState::Selected;
+        if let Some(octave_key) = synth_state.keymap.key_for(selected_octave) {
+            self.key_data[octave_key].state = KeyState::Selected;
+        }
 
         for key_index in 0..21 {
+            let key_type = KeystrikeIlluminator::keytype_for_index(&synth_state.keymap, key_index);
+            self.key_types[key_index] = key_type;
+
+            // Released notes fade with the synth's release, octave keys at the default rate
+            let fade_ms = match key_type {
+                KeyType::Normal => KeyFadeAnimation::fade_ms(synth_state.release_ms),
+                KeyType::Octave => KeyFadeAnimation::fade_ms(0),
+            };
+
             let mut key_data = &mut self.key_data[key_index];
 
             match key_data.state {
                 KeyState::Off => {
-                    if keyboard_state.state[key_index] {
+                    if held_keys.contains(key_index) {
                         key_data.state = KeyState::Pressed;
                         key_data.counter = 0;
 
@@ -132,21 +158,22 @@ impl Illuminator for KeystrikeIlluminator {
                     }
                 }
                 KeyState::Pressed => {
-                    if !keyboard_state.state[key_index] {
+                    if !held_keys.contains(key_index) {
                         let previous_color =
-                            KeystrikeIlluminator::compute_pixel_for_index(key_index, key_data);
+                            KeystrikeIlluminator::compute_pixel(key_type, key_data);
 
                         let previous_color = previous_color.unwrap_or(RGB8::default());
 
                         key_data.state = KeyState::Fade;
                         key_data.counter = 0;
+                        key_data.fade_ms = fade_ms;
                         key_data.data = previous_color.serialize();
                     } else {
                         key_data.counter += delta_t_ms;
                     }
                 }
                 KeyState::Fade => {
-                    if keyboard_state.state[key_index] {
+                    if held_keys.contains(key_index) {
                         key_data.state = KeyState::Pressed;
 
                         adjacency_recursion(
@@ -166,14 +193,14 @@ impl Illuminator for KeystrikeIlluminator {
                     } else {
                         key_data.counter += delta_t_ms;
 
-                        if KeyFadeAnimation::is_complete(key_data.counter) {
+                        if KeyFadeAnimation::is_complete(key_data.counter, key_data.fade_ms) {
                             key_data.state = KeyState::Off;
                             key_data.counter = 0;
                         }
                     }
                 }
                 KeyState::Radiant => {
-                    if keyboard_state.state[key_index] {
+                    if held_keys.contains(key_index) {
                         key_data.state = KeyState::Pressed;
 
                         adjacency_recursion(
@@ -192,25 +219,27 @@ impl Illuminator for KeystrikeIlluminator {
                         );
                     } else if key_data.counter > 50 {
                         let previous_color =
-                            KeystrikeIlluminator::compute_pixel_for_index(key_index, key_data);
+                            KeystrikeIlluminator::compute_pixel(key_type, key_data);
 
                         let previous_color = previous_color.unwrap_or(RGB8::default());
 
                         key_data.state = KeyState::Fade;
                         key_data.counter = 0;
+                        key_data.fade_ms = fade_ms;
                         key_data.data = previous_color.serialize();
                     } else {
                         key_data.counter += delta_t_ms;
                     }
                 }
                 KeyState::Selected => {
-                    if synth_state.octave != (key_index as u8 + 1) {
+                    if synth_state.keymap.action(key_index) != selected_octave {
                         //Fade previously selected octave
                         let previous_color =
-                            KeystrikeIlluminator::compute_pixel_for_index(key_index, key_data);
+                            KeystrikeIlluminator::compute_pixel(key_type, key_data);
                         let previous_color = previous_color.unwrap_or(RGB8::default());
                         key_data.state = KeyState::Fade;
                         key_data.counter = 0;
+                        key_data.fade_ms = fade_ms;
                         key_data.data = previous_color.serialize();
                     } else {
                         key_data.counter += delta_t_ms;
@@ -229,7 +258,7 @@ impl Illuminator for KeystrikeIlluminator {
 
             // rprintln!("K");
 
-            let color = KeystrikeIlluminator::compute_pixel_for_index(key_index, key_data);
+            let color = KeystrikeIlluminator::compute_pixel(self.key_types[key_index], key_data);
 
             if color.is_some() {
                 leds[key_index] = color.unwrap();
@@ -308,7 +337,7 @@ mod test {
         let mut synth_state = synth_engine::SynthState::new();
 
         synth_state.octave = 4;
-        keyboard_state.state[18] = true;
+        keyboard_state.state.insert(18);
 
         illuminator.update(0, &keyboard_state, &synth_state);
 
@@ -357,7 +386,7 @@ mod test {
         let mut synth_state = synth_engine::SynthState::new();
 
         synth_state.octave = 4;
-        keyboard_state.state[18] = true;
+        keyboard_state.state.insert(18);
 
         illuminator.update(0, &keyboard_state, &synth_state);
 
@@ -408,11 +437,11 @@ mod test {
         let mut synth_state = synth_engine::SynthState::new();
 
         synth_state.octave = 4;
-        keyboard_state.state[18] = true;
+        keyboard_state.state.insert(18);
 
         illuminator.update(0, &keyboard_state, &synth_state);
 
-        keyboard_state.state[18] = false;
+        keyboard_state.state.remove(18);
 
         illuminator.update(10, &keyboard_state, &synth_state);
 
@@ -469,11 +498,11 @@ mod test {
         let mut synth_state = synth_engine::SynthState::new();
 
         synth_state.octave = 4;
-        keyboard_state.state[18] = true;
+        keyboard_state.state.insert(18);
 
         illuminator.update(0, &keyboard_state, &synth_state);
 
-        keyboard_state.state[18] = false;
+        keyboard_state.state.remove(18);
 
         //First update to Fade sets counter to zero.  Also sets radiant keys to radiant expiration
         illuminator.update(50, &keyboard_state, &synth_state);
@@ -497,4 +526,60 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn test_released_key_fades_over_release_ms() {
+        let mut illuminator = super::KeystrikeIlluminator::new();
+
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut synth_state = synth_engine::SynthState::new();
+
+        synth_state.release_ms = 100;
+        keyboard_state.state.insert(13);
+
+        illuminator.update(0, &keyboard_state, &synth_state);
+
+        keyboard_state.state.remove(13);
+        illuminator.update(0, &keyboard_state, &synth_state);
+        assert_eq!(illuminator.key_data[13].state, super::KeyState::Fade);
+
+        illuminator.update(100, &keyboard_state, &synth_state);
+        assert_eq!(illuminator.key_data[13].state, super::KeyState::Fade);
+
+        illuminator.update(2, &keyboard_state, &synth_state);
+        assert_eq!(illuminator.key_data[13].state, super::KeyState::Off);
+    }
+
+    #[test]
+    fn test_long_release_fades_with_short_ticks() {
+        let mut illuminator = super::KeystrikeIlluminator::new();
+
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut synth_state = synth_engine::SynthState::new();
+
+        synth_state.release_ms = 3000;
+        keyboard_state.state.insert(13);
+
+        illuminator.update(0, &keyboard_state, &synth_state);
+
+        keyboard_state.state.remove(13);
+        illuminator.update(0, &keyboard_state, &synth_state);
+        let start = illuminator.key_data[13].data;
+
+        for _ in 0..750 {
+            illuminator.update(2, &keyboard_state, &synth_state);
+        }
+
+        let mut leds = [RGB8::default(); 21];
+        illuminator.render(&mut leds);
+        assert_eq!(leds[13], RGB8::deserialize(start).fade(RGB8::default(), 50));
+
+        for _ in 0..750 {
+            illuminator.update(2, &keyboard_state, &synth_state);
+        }
+        assert_eq!(illuminator.key_data[13].state, super::KeyState::Fade);
+
+        illuminator.update(2, &keyboard_state, &synth_state);
+        assert_eq!(illuminator.key_data[13].state, super::KeyState::Off);
+    }
 }
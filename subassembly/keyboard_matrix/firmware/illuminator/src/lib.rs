@@ -14,7 +14,7 @@ use rainbow_pattern_illuminator::RainbowPatternIlluminator;
 use firework_pattern_illuminator::FireworkPatternIlluminator;
 
 use keyboard_matrix::KeyboardState;
-use synth_engine::SynthState;
+use synth_engine::{LooperState, SynthState};
 
 use smart_leds::{hsv::RGB8, SmartLedsWrite};
 
@@ -58,7 +58,9 @@ where
     pub fn update(&mut self, delta_t_ms: u32, keyboard_state: &KeyboardState, synth_state: &SynthState) {
         self.total_time_ms = self.total_time_ms.wrapping_add(delta_t_ms);
 
-        if keyboard_state.depressed_count == 0 {
+        let looping = synth_state.looper.state() != LooperState::Stopped;
+
+        if keyboard_state.state.is_empty() && synth_state.external_notes.is_empty() && !looping {
             self.idle_time_ms = self.idle_time_ms.saturating_add(delta_t_ms);
         } else {
             self.idle_time_ms = 0;
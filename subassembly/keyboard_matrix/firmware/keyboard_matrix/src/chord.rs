@@ -0,0 +1,284 @@
+use crate::event_queue::EventQueue;
+use crate::key_event::KeyEventKind;
+use crate::keyboard_state::KeyboardState;
+
+/// A registered key combination.  `id` is reported in `ChordEvent` so callers can tell chords apart.
+pub struct Chord {
+    pub id: u8,
+    pub keys: &'static [u8],
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChordEvent {
+    pub id: u8,
+    pub kind: KeyEventKind,
+    pub timestamp_ms: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MemberState {
+    Idle,
+    /// Pressed, held back from the output while waiting to see if a chord completes.
+    Pending,
+    /// Part of a recognized chord.  Suppressed from the output until released.
+    Consumed,
+    /// Released while pending.  Shown as pressed for one update, then released.
+    ReleaseNext,
+}
+
+/// Recognizes registered chords and removes their member keys from the keyboard state.
+///
+/// Presses of keys belonging to any chord are each held back for up to `window_ms` from their own press.  If
+/// every key of a chord is pressed while the others are still held back a chord press is reported and the keys
+/// are suppressed until released.  Otherwise the held back presses are passed on late.
+pub struct ChordDetector<const KEYS: usize> {
+    chords: &'static [Chord],
+    window_ms: u32,
+    members: [MemberState; KEYS],
+    pending_since_ms: [u32; KEYS],
+    active_chord: Option<u8>,
+    output: KeyboardState<KEYS>,
+}
+
+impl<const KEYS: usize> ChordDetector<KEYS> {
+    pub fn new(chords: &'static [Chord], window_ms: u32) -> Self {
+        Self {
+            chords,
+            window_ms,
+            members: [MemberState::Idle; KEYS],
+            pending_since_ms: [0; KEYS],
+            active_chord: None,
+            output: KeyboardState::default(),
+        }
+    }
+
+    fn is_chord_member(&self, key: usize) -> bool {
+        self.chords.iter().any(|chord| chord.keys.contains(&(key as u8)))
+    }
+
+    /// Filters `keyboard_state`, queueing chord events and returning the state with chord keys removed.
+    pub fn update<const N: usize>(
+        &mut self,
+        keyboard_state: &KeyboardState<KEYS>,
+        now_ms: u32,
+        events: &mut EventQueue<ChordEvent, N>,
+    ) -> KeyboardState<KEYS> {
+        let mut output_state = keyboard_state.state;
+
+        for key in keyboard_state.pressed {
+            if self.is_chord_member(key) {
+                self.pending_since_ms[key] = now_ms;
+                self.members[key] = MemberState::Pending;
+            }
+        }
+
+        if let Some(chord) = self.completed_chord(keyboard_state) {
+            for key in chord.keys {
+                self.members[*key as usize] = MemberState::Consumed;
+            }
+            self.active_chord = Some(chord.id);
+
+            let _ = events.push(ChordEvent {
+                id: chord.id,
+                kind: KeyEventKind::Press,
+                timestamp_ms: now_ms,
+            });
+        }
+
+        for key in 0..KEYS {
+            match self.members[key] {
+                MemberState::Idle => {}
+                MemberState::Pending => {
+                    if !keyboard_state.state.contains(key) {
+                        self.members[key] = MemberState::ReleaseNext;
+                        output_state.insert(key);
+                    } else if now_ms.wrapping_sub(self.pending_since_ms[key]) >= self.window_ms {
+                        self.members[key] = MemberState::Idle;
+                    } else {
+                        output_state.remove(key);
+                    }
+                }
+                MemberState::Consumed => {
+                    output_state.remove(key);
+
+                    if !keyboard_state.state.contains(key) {
+                        self.members[key] = MemberState::Idle;
+
+                        if let Some(id) = self.active_chord.take() {
+                            let _ = events.push(ChordEvent {
+                                id,
+                                kind: KeyEventKind::Release,
+                                timestamp_ms: now_ms,
+                            });
+                        }
+                    }
+                }
+                MemberState::ReleaseNext => {
+                    self.members[key] = MemberState::Idle;
+                }
+            }
+        }
+
+        self.output = self.output.build_new(output_state);
+
+        self.output
+    }
+
+    fn completed_chord(&self, keyboard_state: &KeyboardState<KEYS>) -> Option<&'static Chord> {
+        self.chords.iter().find(|chord| {
+            chord.keys.iter().all(|key| {
+                let key = *key as usize;
+                keyboard_state.state.contains(key) && self.members[key] == MemberState::Pending
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_set::KeySet;
+
+    static CHORDS: [Chord; 2] = [Chord { id: 1, keys: &[0, 3] }, Chord { id: 2, keys: &[1, 2, 3] }];
+
+    #[test]
+    fn test_non_member_keys_pass_through() {
+        let mut detector = ChordDetector::<5>::new(&CHORDS, 50);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<ChordEvent, 4>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false, false, false, true]));
+        let result = detector.update(&keyboard_state, 0, &mut events);
+
+        assert!(result.pressed.contains(4));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_chord_reports_event_and_suppresses_keys() {
+        let mut detector = ChordDetector::<5>::new(&CHORDS, 50);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<ChordEvent, 4>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false, false, false, false]));
+        let result = detector.update(&keyboard_state, 0, &mut events);
+        assert!(!result.state.contains(0));
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false, false, true, false]));
+        let result = detector.update(&keyboard_state, 20, &mut events);
+        assert!(!result.state.contains(0));
+        assert!(!result.state.contains(3));
+
+        assert_eq!(
+            events.pop(),
+            Some(ChordEvent { id: 1, kind: KeyEventKind::Press, timestamp_ms: 20 })
+        );
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false, false, true, false]));
+        let result = detector.update(&keyboard_state, 500, &mut events);
+        assert_eq!(result.state.len(), 0);
+    }
+
+    #[test]
+    fn test_chord_release_reported_once() {
+        let mut detector = ChordDetector::<5>::new(&CHORDS, 50);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<ChordEvent, 4>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false, false, true, false]));
+        detector.update(&keyboard_state, 0, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false, false, true, false]));
+        detector.update(&keyboard_state, 100, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false, false, false, false]));
+        let result = detector.update(&keyboard_state, 120, &mut events);
+
+        assert_eq!(events.pop().map(|event| event.kind), Some(KeyEventKind::Press));
+        assert_eq!(
+            events.pop(),
+            Some(ChordEvent { id: 1, kind: KeyEventKind::Release, timestamp_ms: 100 })
+        );
+        assert_eq!(events.pop(), None);
+        assert_eq!(result.released.len(), 0);
+    }
+
+    #[test]
+    fn test_lone_member_key_passed_on_after_window() {
+        let mut detector = ChordDetector::<5>::new(&CHORDS, 50);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<ChordEvent, 4>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false, false, false, false]));
+        let result = detector.update(&keyboard_state, 0, &mut events);
+        assert!(!result.state.contains(0));
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false, false, false, false]));
+        let result = detector.update(&keyboard_state, 49, &mut events);
+        assert!(!result.state.contains(0));
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false, false, false, false]));
+        let result = detector.update(&keyboard_state, 50, &mut events);
+        assert!(result.pressed.contains(0));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_quick_member_tap_passed_on_as_press_then_release() {
+        let mut detector = ChordDetector::<5>::new(&CHORDS, 50);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<ChordEvent, 4>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false, false, true, false]));
+        detector.update(&keyboard_state, 0, &mut events);
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([false; 5]));
+        let result = detector.update(&keyboard_state, 10, &mut events);
+        assert!(result.pressed.contains(3));
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([false; 5]));
+        let result = detector.update(&keyboard_state, 20, &mut events);
+        assert!(result.released.contains(3));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_three_key_chord() {
+        let mut detector = ChordDetector::<5>::new(&CHORDS, 50);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<ChordEvent, 4>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, true, false, false, false]));
+        detector.update(&keyboard_state, 0, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, true, true, false, false]));
+        detector.update(&keyboard_state, 10, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, true, true, true, false]));
+        let result = detector.update(&keyboard_state, 30, &mut events);
+
+        assert_eq!(result.state.len(), 0);
+        assert_eq!(events.pop().map(|event| event.id), Some(2));
+    }
+
+    #[test]
+    fn test_late_member_gets_its_own_window() {
+        let mut detector = ChordDetector::<5>::new(&CHORDS, 50);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<ChordEvent, 4>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, true, false, false, false]));
+        detector.update(&keyboard_state, 0, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, true, false, false, true]));
+        detector.update(&keyboard_state, 10, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false, false, true, true]));
+        let result = detector.update(&keyboard_state, 40, &mut events);
+        assert!(!result.state.contains(3));
+
+        // Key 1 went out as a tap, key 3 is still held back until 50ms after its own press
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false, false, true, true]));
+        let result = detector.update(&keyboard_state, 60, &mut events);
+        assert!(!result.state.contains(3));
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false, false, true, true]));
+        let result = detector.update(&keyboard_state, 90, &mut events);
+        assert!(result.pressed.contains(3));
+        assert!(events.is_empty());
+    }
+}
@@ -0,0 +1,282 @@
+/// Filters raw matrix readings into a debounced key state.
+///
+/// Timings are configured in milliseconds and evaluated against the `now_ms` timestamp supplied with each
+/// scan, so behavior does not depend on how often the main loop runs.  Timestamps may wrap.
+pub trait Debouncer<const KEYS: usize> {
+    fn debounce(&mut self, raw: &[bool; KEYS], now_ms: u32) -> [bool; KEYS];
+}
+
+/// Reports a change as soon as it is seen, then ignores the key for `hold_off_ms`.
+///
+/// Lowest latency, but a single noise spike is reported as a full press.
+pub struct EagerDebouncer<const KEYS: usize> {
+    hold_off_ms: u32,
+    state: [bool; KEYS],
+    changed_ms: [u32; KEYS],
+    holding: [bool; KEYS],
+}
+
+impl<const KEYS: usize> EagerDebouncer<KEYS> {
+    pub fn new(hold_off_ms: u32) -> Self {
+        Self {
+            hold_off_ms,
+            state: [false; KEYS],
+            changed_ms: [0; KEYS],
+            holding: [false; KEYS],
+        }
+    }
+}
+
+impl<const KEYS: usize> Debouncer<KEYS> for EagerDebouncer<KEYS> {
+    fn debounce(&mut self, raw: &[bool; KEYS], now_ms: u32) -> [bool; KEYS] {
+        for (i, raw) in raw.iter().enumerate() {
+            if self.holding[i] && now_ms.wrapping_sub(self.changed_ms[i]) >= self.hold_off_ms {
+                self.holding[i] = false;
+            }
+
+            if !self.holding[i] && *raw != self.state[i] {
+                self.state[i] = *raw;
+                self.changed_ms[i] = now_ms;
+                self.holding[i] = true;
+            }
+        }
+
+        self.state
+    }
+}
+
+/// Reports a change only once the raw reading has held the new value for `stable_ms`.
+///
+/// Rejects noise, at the cost of `stable_ms` latency on every press and release.
+pub struct DeferredDebouncer<const KEYS: usize> {
+    stable_ms: u32,
+    state: [bool; KEYS],
+    pending_ms: [u32; KEYS],
+    pending: [bool; KEYS],
+}
+
+impl<const KEYS: usize> DeferredDebouncer<KEYS> {
+    pub fn new(stable_ms: u32) -> Self {
+        Self {
+            stable_ms,
+            state: [false; KEYS],
+            pending_ms: [0; KEYS],
+            pending: [false; KEYS],
+        }
+    }
+}
+
+impl<const KEYS: usize> Debouncer<KEYS> for DeferredDebouncer<KEYS> {
+    fn debounce(&mut self, raw: &[bool; KEYS], now_ms: u32) -> [bool; KEYS] {
+        for (i, raw) in raw.iter().enumerate() {
+            if *raw == self.state[i] {
+                self.pending[i] = false;
+                continue;
+            }
+
+            if !self.pending[i] {
+                self.pending[i] = true;
+                self.pending_ms[i] = now_ms;
+            }
+
+            if now_ms.wrapping_sub(self.pending_ms[i]) >= self.stable_ms {
+                self.state[i] = *raw;
+                self.pending[i] = false;
+            }
+        }
+
+        self.state
+    }
+}
+
+/// Integrates time spent closed against time spent open, reporting pressed once the integrator reaches
+/// `threshold_ms` and released once it drains back to zero.
+///
+/// Tolerates intermittent contact better than `DeferredDebouncer` since a short glitch only drains the
+/// integrator rather than restarting the window.
+pub struct IntegratorDebouncer<const KEYS: usize> {
+    threshold_ms: u16,
+    state: [bool; KEYS],
+    integrator: [u16; KEYS],
+    last_ms: Option<u32>,
+}
+
+impl<const KEYS: usize> IntegratorDebouncer<KEYS> {
+    pub fn new(threshold_ms: u16) -> Self {
+        Self {
+            threshold_ms,
+            state: [false; KEYS],
+            integrator: [0; KEYS],
+            last_ms: None,
+        }
+    }
+}
+
+impl<const KEYS: usize> Debouncer<KEYS> for IntegratorDebouncer<KEYS> {
+    fn debounce(&mut self, raw: &[bool; KEYS], now_ms: u32) -> [bool; KEYS] {
+        let delta_ms = match self.last_ms {
+            Some(last_ms) => now_ms.wrapping_sub(last_ms),
+            None => 0,
+        };
+        let delta_ms = delta_ms.min(self.threshold_ms as u32) as u16;
+
+        self.last_ms = Some(now_ms);
+
+        for (i, raw) in raw.iter().enumerate() {
+            if *raw {
+                self.integrator[i] = self.integrator[i].saturating_add(delta_ms).min(self.threshold_ms);
+            } else {
+                self.integrator[i] = self.integrator[i].saturating_sub(delta_ms);
+            }
+
+            if self.integrator[i] >= self.threshold_ms {
+                self.state[i] = true;
+            } else if self.integrator[i] == 0 {
+                self.state[i] = false;
+            }
+        }
+
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds a trace of (timestamp, raw reading) for a single key and returns the debounced output
+    /// after each step.
+    fn run_trace<D: Debouncer<1>, const N: usize>(debouncer: &mut D, trace: [(u32, bool); N]) -> [bool; N] {
+        let mut result = [false; N];
+
+        for (step, (now_ms, raw)) in trace.iter().enumerate() {
+            result[step] = debouncer.debounce(&[*raw], *now_ms)[0];
+        }
+
+        result
+    }
+
+    const PRESS_WITH_BOUNCE: [(u32, bool); 8] = [
+        (0, false),
+        (1, true),
+        (2, false),
+        (3, true),
+        (4, false),
+        (5, true),
+        (10, true),
+        (20, true),
+    ];
+
+    const RELEASE_WITH_BOUNCE: [(u32, bool); 8] = [
+        (0, true),
+        (20, true),
+        (21, false),
+        (22, true),
+        (23, false),
+        (24, false),
+        (30, false),
+        (45, false),
+    ];
+
+    #[test]
+    fn test_eager_reports_press_immediately() {
+        let mut debouncer = EagerDebouncer::new(5);
+
+        let result = run_trace(&mut debouncer, PRESS_WITH_BOUNCE);
+
+        assert_eq!(result, [false, true, true, true, true, true, true, true]);
+    }
+
+    #[test]
+    fn test_eager_reports_release_immediately() {
+        let mut debouncer = EagerDebouncer::new(5);
+
+        let result = run_trace(&mut debouncer, RELEASE_WITH_BOUNCE);
+
+        assert_eq!(result, [true, true, false, false, false, false, false, false]);
+    }
+
+    #[test]
+    fn test_eager_accepts_change_after_hold_off() {
+        let mut debouncer = EagerDebouncer::new(5);
+
+        let result = run_trace(&mut debouncer, [(0, true), (4, false), (5, false), (6, true)]);
+
+        assert_eq!(result, [true, true, false, false]);
+    }
+
+    #[test]
+    fn test_eager_handles_timestamp_wrap() {
+        let mut debouncer = EagerDebouncer::new(5);
+
+        let result = run_trace(&mut debouncer, [(u32::MAX - 1, true), (1, true), (4, false)]);
+
+        assert_eq!(result, [true, true, false]);
+    }
+
+    #[test]
+    fn test_deferred_waits_for_stable_press() {
+        let mut debouncer = DeferredDebouncer::new(5);
+
+        let result = run_trace(&mut debouncer, PRESS_WITH_BOUNCE);
+
+        assert_eq!(result, [false, false, false, false, false, false, true, true]);
+    }
+
+    #[test]
+    fn test_deferred_waits_for_stable_release() {
+        let mut debouncer = DeferredDebouncer::new(5);
+
+        let result = run_trace(&mut debouncer, RELEASE_WITH_BOUNCE);
+
+        assert_eq!(result, [false, true, true, true, true, true, false, false]);
+    }
+
+    #[test]
+    fn test_deferred_rejects_glitch() {
+        let mut debouncer = DeferredDebouncer::new(5);
+
+        let result = run_trace(&mut debouncer, [(0, false), (1, true), (2, false), (10, false)]);
+
+        assert_eq!(result, [false, false, false, false]);
+    }
+
+    #[test]
+    fn test_integrator_reports_press_after_threshold() {
+        let mut debouncer = IntegratorDebouncer::new(5);
+
+        let result = run_trace(&mut debouncer, PRESS_WITH_BOUNCE);
+
+        assert_eq!(result, [false, false, false, false, false, false, true, true]);
+    }
+
+    #[test]
+    fn test_integrator_reports_release_once_drained() {
+        let mut debouncer = IntegratorDebouncer::new(5);
+
+        let result = run_trace(&mut debouncer, RELEASE_WITH_BOUNCE);
+
+        assert_eq!(result, [false, true, true, true, true, true, false, false]);
+    }
+
+    #[test]
+    fn test_integrator_tolerates_intermittent_contact() {
+        let mut debouncer = IntegratorDebouncer::new(4);
+
+        let result = run_trace(
+            &mut debouncer,
+            [(0, true), (4, true), (5, false), (6, true), (7, false), (8, true), (9, true)],
+        );
+
+        assert_eq!(result, [false, true, true, true, true, true, true]);
+    }
+
+    #[test]
+    fn test_integrator_with_long_threshold_does_not_overflow() {
+        let mut debouncer = IntegratorDebouncer::new(40_000);
+
+        let result = run_trace(&mut debouncer, [(0, true), (39_999, true), (79_999, true), (80_000, false)]);
+
+        assert_eq!(result, [false, false, true, true]);
+    }
+}
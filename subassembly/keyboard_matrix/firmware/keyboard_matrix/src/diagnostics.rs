@@ -0,0 +1,262 @@
+use crate::key_set::KeySet;
+
+/// Switch health statistics for a single key.  Counters saturate rather than wrap.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KeyDiagnostics {
+    /// Changes of the raw reading.
+    pub raw_transitions: u16,
+    /// Changes of the debounced state.
+    pub transitions: u16,
+    /// Raw changes the debouncer discarded because the reading returned before they were accepted.
+    pub rejected: u16,
+    /// How long the key has been continuously held, zero while released.
+    pub held_ms: u32,
+    /// Held for at least the stuck key limit.  Cleared on release.
+    pub stuck: bool,
+
+    pressed_ms: u32,
+    raw: bool,
+    debounced: bool,
+}
+
+impl KeyDiagnostics {
+    /// Raw changes beyond those the debounced state followed.
+    pub fn bounces(&self) -> u16 {
+        self.raw_transitions.saturating_sub(self.transitions)
+    }
+}
+
+/// Tracks per key bounce, rejection and hold statistics across scans.
+pub struct Diagnostics<const KEYS: usize = 21> {
+    pub keys: [KeyDiagnostics; KEYS],
+    /// Number of keys currently stuck.
+    pub stuck_count: u8,
+    /// Scans abandoned on a pin error.  Saturates rather than wraps.
+    pub scan_errors: u16,
+
+    stuck_limit_ms: u32,
+    mask_stuck: bool,
+}
+
+impl<const KEYS: usize> Default for Diagnostics<KEYS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const KEYS: usize> Diagnostics<KEYS> {
+    /// Creates diagnostics with stuck key detection disabled.
+    pub fn new() -> Self {
+        Self {
+            keys: [KeyDiagnostics::default(); KEYS],
+            stuck_count: 0,
+            scan_errors: 0,
+
+            stuck_limit_ms: 0,
+            mask_stuck: false,
+        }
+    }
+
+    /// Keys held for `limit_ms` are reported as stuck, zero disables detection.  When `mask` is set stuck
+    /// keys are also reported as released until they are physically released.
+    pub fn set_stuck_limit(&mut self, limit_ms: u32, mask: bool) {
+        self.stuck_limit_ms = limit_ms;
+        self.mask_stuck = mask;
+    }
+
+    /// Records one scan, returning `debounced` with stuck keys masked if enabled.
+    pub fn update(&mut self, raw: &[bool; KEYS], debounced: &[bool; KEYS], now_ms: u32) -> [bool; KEYS] {
+        let mut output = *debounced;
+        let mut stuck_count = 0;
+
+        for (key, diagnostics) in self.keys.iter_mut().enumerate() {
+            let was_disagreeing = diagnostics.raw != diagnostics.debounced;
+
+            if raw[key] != diagnostics.raw {
+                diagnostics.raw_transitions = diagnostics.raw_transitions.saturating_add(1);
+
+                if was_disagreeing && raw[key] == debounced[key] && debounced[key] == diagnostics.debounced {
+                    diagnostics.rejected = diagnostics.rejected.saturating_add(1);
+                }
+            }
+
+            if debounced[key] != diagnostics.debounced {
+                diagnostics.transitions = diagnostics.transitions.saturating_add(1);
+                diagnostics.pressed_ms = now_ms;
+            }
+
+            diagnostics.raw = raw[key];
+            diagnostics.debounced = debounced[key];
+
+            if debounced[key] {
+                diagnostics.held_ms = now_ms.wrapping_sub(diagnostics.pressed_ms);
+                diagnostics.stuck = self.stuck_limit_ms > 0 && diagnostics.held_ms >= self.stuck_limit_ms;
+            } else {
+                diagnostics.held_ms = 0;
+                diagnostics.stuck = false;
+            }
+
+            if diagnostics.stuck {
+                stuck_count += 1;
+                output[key] &= !self.mask_stuck;
+            }
+        }
+
+        self.stuck_count = stuck_count;
+
+        output
+    }
+
+    /// Clears the counters, keeping hold times and stuck flags.
+    pub fn reset_counts(&mut self) {
+        for diagnostics in self.keys.iter_mut() {
+            diagnostics.raw_transitions = 0;
+            diagnostics.transitions = 0;
+            diagnostics.rejected = 0;
+        }
+        self.scan_errors = 0;
+    }
+
+    pub fn stuck_keys(&self) -> KeySet {
+        self.keys
+            .iter()
+            .enumerate()
+            .filter(|(_, diagnostics)| diagnostics.stuck)
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Forgets that `keys` were held, for keys seen released outside a scan.  They are no longer stuck and
+    /// count as newly pressed if held again at the next update.
+    pub fn clear_stuck(&mut self, keys: KeySet) {
+        for key in keys.iter().filter(|key| *key < KEYS) {
+            let diagnostics = &mut self.keys[key];
+
+            if diagnostics.stuck {
+                self.stuck_count -= 1;
+            }
+
+            diagnostics.stuck = false;
+            diagnostics.held_ms = 0;
+            diagnostics.debounced = false;
+        }
+    }
+
+    /// Stuck keys currently reported as released.
+    pub fn masked_keys(&self) -> KeySet {
+        if self.mask_stuck {
+            self.stuck_keys()
+        } else {
+            KeySet::EMPTY
+        }
+    }
+
+    /// The key with the most rejected transitions, if any were rejected.
+    pub fn noisiest_key(&self) -> Option<(usize, &KeyDiagnostics)> {
+        self.keys
+            .iter()
+            .enumerate()
+            .filter(|(_, diagnostics)| diagnostics.rejected > 0)
+            .max_by_key(|(_, diagnostics)| diagnostics.rejected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_press_has_no_bounces() {
+        let mut diagnostics: Diagnostics<1> = Diagnostics::new();
+
+        diagnostics.update(&[true], &[true], 0);
+        diagnostics.update(&[false], &[false], 100);
+
+        assert_eq!(diagnostics.keys[0].transitions, 2);
+        assert_eq!(diagnostics.keys[0].bounces(), 0);
+        assert_eq!(diagnostics.keys[0].rejected, 0);
+    }
+
+    #[test]
+    fn test_late_debounce_is_not_rejected() {
+        let mut diagnostics: Diagnostics<1> = Diagnostics::new();
+
+        diagnostics.update(&[true], &[false], 0);
+        diagnostics.update(&[true], &[true], 5);
+
+        assert_eq!(diagnostics.keys[0].raw_transitions, 1);
+        assert_eq!(diagnostics.keys[0].transitions, 1);
+        assert_eq!(diagnostics.keys[0].rejected, 0);
+    }
+
+    #[test]
+    fn test_bouncing_press_counts_bounces_and_rejections() {
+        let mut diagnostics: Diagnostics<1> = Diagnostics::new();
+
+        // Eager debounce accepts the first edge and ignores the bounce that follows
+        diagnostics.update(&[true], &[true], 0);
+        diagnostics.update(&[false], &[true], 1);
+        diagnostics.update(&[true], &[true], 2);
+        diagnostics.update(&[false], &[true], 3);
+        diagnostics.update(&[true], &[true], 4);
+
+        assert_eq!(diagnostics.keys[0].transitions, 1);
+        assert_eq!(diagnostics.keys[0].bounces(), 4);
+        assert_eq!(diagnostics.keys[0].rejected, 2);
+        assert_eq!(diagnostics.noisiest_key().map(|(key, _)| key), Some(0));
+    }
+
+    #[test]
+    fn test_held_time_tracks_debounced_press() {
+        let mut diagnostics: Diagnostics<2> = Diagnostics::new();
+
+        diagnostics.update(&[false, true], &[false, true], 100);
+        diagnostics.update(&[false, true], &[false, true], 350);
+
+        assert_eq!(diagnostics.keys[0].held_ms, 0);
+        assert_eq!(diagnostics.keys[1].held_ms, 250);
+
+        diagnostics.update(&[false, false], &[false, false], 400);
+        assert_eq!(diagnostics.keys[1].held_ms, 0);
+    }
+
+    #[test]
+    fn test_stuck_key_reported_without_masking() {
+        let mut diagnostics: Diagnostics<2> = Diagnostics::new();
+        diagnostics.set_stuck_limit(1000, false);
+
+        diagnostics.update(&[false, true], &[false, true], 0);
+        let output = diagnostics.update(&[false, true], &[false, true], 1000);
+
+        assert!(diagnostics.keys[1].stuck);
+        assert_eq!(diagnostics.stuck_count, 1);
+        assert_eq!(diagnostics.stuck_keys(), KeySet::from_bits(0b10));
+        assert_eq!(output, [false, true]);
+    }
+
+    #[test]
+    fn test_stuck_key_masked_until_released() {
+        let mut diagnostics: Diagnostics<1> = Diagnostics::new();
+        diagnostics.set_stuck_limit(1000, true);
+
+        assert_eq!(diagnostics.update(&[true], &[true], 0), [true]);
+        assert_eq!(diagnostics.update(&[true], &[true], 999), [true]);
+        assert_eq!(diagnostics.update(&[true], &[true], 1000), [false]);
+
+        diagnostics.update(&[false], &[false], 1100);
+        assert!(!diagnostics.keys[0].stuck);
+        assert_eq!(diagnostics.stuck_count, 0);
+
+        assert_eq!(diagnostics.update(&[true], &[true], 1200), [true]);
+    }
+
+    #[test]
+    fn test_stuck_detection_disabled_by_default() {
+        let mut diagnostics: Diagnostics<1> = Diagnostics::new();
+
+        diagnostics.update(&[true], &[true], 0);
+        diagnostics.update(&[true], &[true], u32::MAX / 2);
+
+        assert!(!diagnostics.keys[0].stuck);
+    }
+}
@@ -0,0 +1,153 @@
+/// Bounded FIFO of events.
+///
+/// When full, new events are rejected rather than overwriting older ones, and the number of rejected
+/// events is kept so consumers can tell their view of the keyboard is incomplete.
+pub struct EventQueue<T, const N: usize> {
+    events: [T; N],
+    head: usize,
+    len: usize,
+    dropped: u16,
+}
+
+impl<T: Copy + Default, const N: usize> Default for EventQueue<T, N> {
+    fn default() -> Self {
+        Self {
+            events: [T::default(); N],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+}
+
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    /// Appends an event, handing it back if the queue is full.
+    pub fn push(&mut self, event: T) -> Result<(), T> {
+        if self.len == N {
+            self.dropped = self.dropped.saturating_add(1);
+
+            return Err(event);
+        }
+
+        self.events[(self.head + self.len) % N] = event;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let event = self.events[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+
+        Some(event)
+    }
+
+    pub fn peek(&self) -> Option<&T> {
+        if self.len == 0 {
+            None
+        } else {
+            Some(&self.events[self.head])
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// True if events have been dropped since the last call to `take_dropped`.
+    pub fn overflowed(&self) -> bool {
+        self.dropped > 0
+    }
+
+    /// Returns the number of events dropped since the last call and resets the count.
+    pub fn take_dropped(&mut self) -> u16 {
+        let dropped = self.dropped;
+        self.dropped = 0;
+
+        dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_event::*;
+
+    fn press(key: u8, timestamp_ms: u32) -> KeyEvent {
+        KeyEvent {
+            key,
+            kind: KeyEventKind::Press,
+            timestamp_ms,
+        }
+    }
+
+    #[test]
+    fn test_empty_queue_pops_none() {
+        let mut queue: EventQueue<KeyEvent, 4> = EventQueue::default();
+
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_events_pop_in_push_order() {
+        let mut queue: EventQueue<KeyEvent, 4> = EventQueue::default();
+
+        queue.push(press(1, 10)).unwrap();
+        queue.push(press(2, 10)).unwrap();
+        queue.push(press(3, 12)).unwrap();
+
+        assert_eq!(queue.pop(), Some(press(1, 10)));
+        assert_eq!(queue.pop(), Some(press(2, 10)));
+        assert_eq!(queue.pop(), Some(press(3, 12)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn test_queue_wraps() {
+        let mut queue: EventQueue<KeyEvent, 2> = EventQueue::default();
+
+        for key in 0..5 {
+            queue.push(press(key, key as u32)).unwrap();
+            assert_eq!(queue.pop(), Some(press(key, key as u32)));
+        }
+
+        assert!(!queue.overflowed());
+    }
+
+    #[test]
+    fn test_full_queue_rejects_and_counts_overflow() {
+        let mut queue: EventQueue<KeyEvent, 2> = EventQueue::default();
+
+        queue.push(press(1, 0)).unwrap();
+        queue.push(press(2, 0)).unwrap();
+
+        assert_eq!(queue.push(press(3, 0)), Err(press(3, 0)));
+        assert_eq!(queue.push(press(4, 0)), Err(press(4, 0)));
+
+        assert!(queue.overflowed());
+        assert_eq!(queue.take_dropped(), 2);
+        assert!(!queue.overflowed());
+
+        assert_eq!(queue.pop(), Some(press(1, 0)));
+        assert_eq!(queue.pop(), Some(press(2, 0)));
+    }
+}
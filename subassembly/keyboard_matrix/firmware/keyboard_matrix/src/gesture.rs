@@ -0,0 +1,291 @@
+use crate::event_queue::EventQueue;
+use crate::keyboard_state::KeyboardState;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GestureKind {
+    /// Short press and release, reported once no second tap follows within `double_tap_gap_ms`.
+    #[default]
+    Tap,
+    /// Two taps, the second starting within `double_tap_gap_ms` of the first release.
+    DoubleTap,
+    /// Key has been held for `hold_ms`.  Reported while the key is still down.
+    Hold,
+    /// Key has been held for `long_press_ms`.  Reported while the key is still down, after `Hold`.
+    LongPress,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GestureEvent {
+    pub key: u8,
+    pub kind: GestureKind,
+    pub timestamp_ms: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GestureConfig {
+    /// Longest press that still counts as a tap.
+    pub tap_max_ms: u32,
+    /// Longest gap between the taps of a double tap.  Zero disables double tap, reporting taps on release.
+    pub double_tap_gap_ms: u32,
+    pub hold_ms: u32,
+    pub long_press_ms: u32,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            tap_max_ms: 200,
+            double_tap_gap_ms: 250,
+            hold_ms: 500,
+            long_press_ms: 1500,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct KeyGesture {
+    pressed_ms: u32,
+    released_ms: u32,
+    hold_sent: bool,
+    long_press_sent: bool,
+    tap_pending: bool,
+}
+
+/// Recognizes tap, double tap, hold and long press gestures from successive keyboard states.
+pub struct GestureRecognizer<const KEYS: usize> {
+    config: GestureConfig,
+    keys: [KeyGesture; KEYS],
+}
+
+impl<const KEYS: usize> GestureRecognizer<KEYS> {
+    pub fn new(config: GestureConfig) -> Self {
+        Self {
+            config,
+            keys: [KeyGesture::default(); KEYS],
+        }
+    }
+
+    pub fn update<const N: usize>(
+        &mut self,
+        keyboard_state: &KeyboardState<KEYS>,
+        now_ms: u32,
+        events: &mut EventQueue<GestureEvent, N>,
+    ) {
+        for (key, gesture) in self.keys.iter_mut().enumerate() {
+            let mut emit = |kind| {
+                // Overflow is counted by the queue for the consumer to report
+                let _ = events.push(GestureEvent {
+                    key: key as u8,
+                    kind,
+                    timestamp_ms: now_ms,
+                });
+            };
+
+            if keyboard_state.pressed.contains(key) {
+                gesture.pressed_ms = now_ms;
+                gesture.hold_sent = false;
+                gesture.long_press_sent = false;
+            }
+
+            if keyboard_state.state.contains(key) {
+                let held_ms = now_ms.wrapping_sub(gesture.pressed_ms);
+
+                if gesture.tap_pending && held_ms > self.config.tap_max_ms {
+                    // Second press turned into a hold, so the first was a lone tap
+                    gesture.tap_pending = false;
+                    emit(GestureKind::Tap);
+                }
+
+                if !gesture.hold_sent && held_ms >= self.config.hold_ms {
+                    gesture.hold_sent = true;
+                    emit(GestureKind::Hold);
+                }
+
+                if !gesture.long_press_sent && held_ms >= self.config.long_press_ms {
+                    gesture.long_press_sent = true;
+                    emit(GestureKind::LongPress);
+                }
+            } else if keyboard_state.released.contains(key) {
+                let held_ms = now_ms.wrapping_sub(gesture.pressed_ms);
+
+                if held_ms <= self.config.tap_max_ms {
+                    if gesture.tap_pending {
+                        gesture.tap_pending = false;
+                        emit(GestureKind::DoubleTap);
+                    } else if self.config.double_tap_gap_ms == 0 {
+                        emit(GestureKind::Tap);
+                    } else {
+                        gesture.tap_pending = true;
+                        gesture.released_ms = now_ms;
+                    }
+                }
+            } else if gesture.tap_pending
+                && now_ms.wrapping_sub(gesture.released_ms) > self.config.double_tap_gap_ms
+            {
+                gesture.tap_pending = false;
+                emit(GestureKind::Tap);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_set::KeySet;
+
+    const CONFIG: GestureConfig = GestureConfig {
+        tap_max_ms: 100,
+        double_tap_gap_ms: 150,
+        hold_ms: 300,
+        long_press_ms: 1000,
+    };
+
+    #[test]
+    fn test_tap_reported_after_double_tap_gap() {
+        let mut recognizer = GestureRecognizer::<2>::new(CONFIG);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<GestureEvent, 8>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 0, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 50, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 150, &mut events);
+        assert_eq!(events.pop().map(|event| event.kind), None);
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 201, &mut events);
+        assert_eq!(events.pop().map(|event| event.kind), Some(GestureKind::Tap));
+        assert_eq!(events.pop().map(|event| event.kind), None);
+    }
+
+    #[test]
+    fn test_tap_reported_on_release_without_double_tap() {
+        let mut recognizer = GestureRecognizer::<2>::new(GestureConfig { double_tap_gap_ms: 0, ..CONFIG });
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<GestureEvent, 8>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 0, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 50, &mut events);
+
+        assert_eq!(events.pop().map(|event| event.kind), Some(GestureKind::Tap));
+    }
+
+    #[test]
+    fn test_double_tap() {
+        let mut recognizer = GestureRecognizer::<2>::new(CONFIG);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<GestureEvent, 8>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 0, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 50, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 120, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 160, &mut events);
+
+        assert_eq!(events.pop().map(|event| event.kind), Some(GestureKind::DoubleTap));
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 1000, &mut events);
+        assert_eq!(events.pop().map(|event| event.kind), None);
+    }
+
+    #[test]
+    fn test_tap_then_hold_reports_tap_then_hold() {
+        let mut recognizer = GestureRecognizer::<2>::new(CONFIG);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<GestureEvent, 8>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 0, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 50, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 120, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 300, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 420, &mut events);
+
+        assert_eq!(events.pop().map(|event| event.kind), Some(GestureKind::Tap));
+        assert_eq!(events.pop().map(|event| event.kind), Some(GestureKind::Hold));
+        assert_eq!(events.pop().map(|event| event.kind), None);
+    }
+
+    #[test]
+    fn test_hold_then_long_press_reported_once() {
+        let mut recognizer = GestureRecognizer::<2>::new(CONFIG);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<GestureEvent, 8>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 0, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 299, &mut events);
+        assert_eq!(events.pop().map(|event| event.kind), None);
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 300, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 600, &mut events);
+        assert_eq!(events.pop().map(|event| event.kind), Some(GestureKind::Hold));
+        assert_eq!(events.pop().map(|event| event.kind), None);
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 1000, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 2000, &mut events);
+        assert_eq!(events.pop().map(|event| event.kind), Some(GestureKind::LongPress));
+        assert_eq!(events.pop().map(|event| event.kind), None);
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 2100, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 3000, &mut events);
+        assert_eq!(events.pop().map(|event| event.kind), None);
+    }
+
+    #[test]
+    fn test_slow_release_is_not_a_tap() {
+        let mut recognizer = GestureRecognizer::<2>::new(CONFIG);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<GestureEvent, 8>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 0, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 150, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([false, false]));
+        recognizer.update(&keyboard_state, 1000, &mut events);
+
+        assert_eq!(events.pop().map(|event| event.kind), None);
+    }
+
+    #[test]
+    fn test_event_carries_key_and_timestamp() {
+        let mut recognizer = GestureRecognizer::<2>::new(CONFIG);
+        let mut keyboard_state = KeyboardState::default();
+        let mut events = EventQueue::<GestureEvent, 8>::default();
+
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 10, &mut events);
+        keyboard_state = keyboard_state.build_new(KeySet::from([true, false]));
+        recognizer.update(&keyboard_state, 310, &mut events);
+
+        assert_eq!(
+            events.pop(),
+            Some(GestureEvent {
+                key: 0,
+                kind: GestureKind::Hold,
+                timestamp_ms: 310
+            })
+        );
+    }
+}
@@ -0,0 +1,80 @@
+/// Finds switches that are part of a closed rectangle in a raw matrix reading.
+///
+/// Without per-key diodes, three closed corners of a rectangle make the fourth read as closed, so once two
+/// rows share two or more closed columns it is impossible to tell which of those switches are really
+/// pressed.  Every switch on such a shared column is reported as ambiguous.
+pub fn find_ghosts<const ROWS: usize, const COLS: usize>(closed: &[[bool; COLS]; ROWS]) -> [[bool; COLS]; ROWS] {
+    let mut ambiguous = [[false; COLS]; ROWS];
+
+    for first_row in 0..ROWS {
+        for second_row in (first_row + 1)..ROWS {
+            let shared = (0..COLS)
+                .filter(|col| closed[first_row][*col] && closed[second_row][*col])
+                .count();
+
+            if shared < 2 {
+                continue;
+            }
+
+            for col in 0..COLS {
+                if closed[first_row][col] && closed[second_row][col] {
+                    ambiguous[first_row][col] = true;
+                    ambiguous[second_row][col] = true;
+                }
+            }
+        }
+    }
+
+    ambiguous
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_keys_has_no_ghosts() {
+        let closed = [[false; 3]; 3];
+
+        assert_eq!(find_ghosts(&closed), [[false; 3]; 3]);
+    }
+
+    #[test]
+    fn test_three_corners_are_not_ambiguous() {
+        let closed = [
+            [true, true, false],
+            [true, false, false],
+            [false, false, false],
+        ];
+
+        assert_eq!(find_ghosts(&closed), [[false; 3]; 3]);
+    }
+
+    #[test]
+    fn test_rectangle_marks_all_four_corners() {
+        let closed = [
+            [true, false, true],
+            [false, true, false],
+            [true, false, true],
+        ];
+
+        let expected = [
+            [true, false, true],
+            [false, false, false],
+            [true, false, true],
+        ];
+
+        assert_eq!(find_ghosts(&closed), expected);
+    }
+
+    #[test]
+    fn test_same_row_chord_is_not_ambiguous() {
+        let closed = [
+            [true, true, true],
+            [false, false, false],
+            [false, false, false],
+        ];
+
+        assert_eq!(find_ghosts(&closed), [[false; 3]; 3]);
+    }
+}
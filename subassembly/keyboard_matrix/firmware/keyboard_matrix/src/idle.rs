@@ -0,0 +1,175 @@
+use crate::keyboard_state::KeyboardState;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScanMode {
+    /// Scan the full matrix every loop.
+    Active,
+    /// Only check `KeyboardMatrix::any_key_closed` when `IdlePolicy::poll_due`.
+    Idle,
+}
+
+/// Decides when to drop from full scanning to slow any-key polling and back.
+///
+/// The keyboard goes idle once no key has been held or changed for `idle_after_ms`, and wakes as soon as a
+/// poll finds a closed key.
+pub struct IdlePolicy {
+    idle_after_ms: u32,
+    poll_interval_ms: u32,
+    mode: ScanMode,
+    last_activity_ms: u32,
+    last_poll_ms: u32,
+}
+
+impl IdlePolicy {
+    pub fn new(idle_after_ms: u32, poll_interval_ms: u32) -> Self {
+        Self {
+            idle_after_ms,
+            poll_interval_ms,
+            mode: ScanMode::Active,
+            last_activity_ms: 0,
+            last_poll_ms: 0,
+        }
+    }
+
+    pub fn mode(&self) -> ScanMode {
+        self.mode
+    }
+
+    /// Records the result of a full scan.
+    pub fn scanned<const KEYS: usize>(&mut self, keyboard_state: &KeyboardState<KEYS>, now_ms: u32) -> ScanMode {
+        let active = !(keyboard_state.state | keyboard_state.pressed | keyboard_state.released).is_empty();
+
+        if active {
+            self.mode = ScanMode::Active;
+            self.last_activity_ms = now_ms;
+        } else if now_ms.wrapping_sub(self.last_activity_ms) >= self.idle_after_ms {
+            self.mode = ScanMode::Idle;
+            self.last_poll_ms = now_ms;
+        }
+
+        self.mode
+    }
+
+    /// Whether an idle poll should be made now.  Always false while active.
+    pub fn poll_due(&self, now_ms: u32) -> bool {
+        self.mode == ScanMode::Idle && now_ms.wrapping_sub(self.last_poll_ms) >= self.poll_interval_ms
+    }
+
+    /// Records the result of `KeyboardMatrix::any_key_closed`.
+    pub fn polled(&mut self, any_closed: bool, now_ms: u32) -> ScanMode {
+        self.last_poll_ms = now_ms;
+
+        if any_closed {
+            self.mode = ScanMode::Active;
+            self.last_activity_ms = now_ms;
+        }
+
+        self.mode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_set::KeySet;
+    use crate::sim::{SimDelay, SimMatrix};
+    use crate::{EagerDebouncer, KeyboardMatrix, KIB_COLS, KIB_LAYOUT, KIB_ROWS};
+
+    fn state(keys: [bool; 2]) -> KeyboardState<2> {
+        KeyboardState::default().build_new(KeySet::from(keys))
+    }
+
+    #[test]
+    fn test_goes_idle_after_quiet_period() {
+        let mut policy = IdlePolicy::new(1000, 50);
+        let released = KeyboardState::<2>::default();
+
+        assert_eq!(policy.scanned(&released, 0), ScanMode::Active);
+        assert_eq!(policy.scanned(&released, 999), ScanMode::Active);
+        assert_eq!(policy.scanned(&released, 1000), ScanMode::Idle);
+    }
+
+    #[test]
+    fn test_held_key_keeps_active() {
+        let mut policy = IdlePolicy::new(1000, 50);
+        let pressed = state([true, false]);
+        let held = pressed.build_new(KeySet::from([true, false]));
+        let released = held.build_new(KeySet::from([false, false]));
+
+        policy.scanned(&pressed, 0);
+        assert_eq!(policy.scanned(&held, 5000), ScanMode::Active);
+        assert_eq!(policy.scanned(&released, 5100), ScanMode::Active);
+        assert_eq!(policy.scanned(&released.build_new(KeySet::from([false, false])), 6099), ScanMode::Active);
+        assert_eq!(policy.scanned(&released.build_new(KeySet::from([false, false])), 6100), ScanMode::Idle);
+    }
+
+    #[test]
+    fn test_polls_at_interval_while_idle() {
+        let mut policy = IdlePolicy::new(100, 50);
+        assert!(!policy.poll_due(0));
+
+        policy.scanned(&KeyboardState::<2>::default(), 100);
+        assert!(!policy.poll_due(149));
+        assert!(policy.poll_due(150));
+
+        assert_eq!(policy.polled(false, 150), ScanMode::Idle);
+        assert!(!policy.poll_due(199));
+        assert!(policy.poll_due(200));
+    }
+
+    #[test]
+    fn test_wakes_on_closed_key() {
+        let mut policy = IdlePolicy::new(100, 50);
+
+        policy.scanned(&KeyboardState::<2>::default(), 100);
+        assert_eq!(policy.polled(true, 150), ScanMode::Active);
+        assert!(!policy.poll_due(1000));
+
+        // The wake counts as activity, so the release scan that follows does not go straight back to idle
+        assert_eq!(policy.scanned(&KeyboardState::<2>::default(), 200), ScanMode::Active);
+        assert_eq!(policy.scanned(&KeyboardState::<2>::default(), 250), ScanMode::Idle);
+    }
+
+    #[test]
+    fn test_masked_stuck_key_stays_idle() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        let mut delay = SimDelay::default();
+        let mut policy = IdlePolicy::new(100, 50);
+        matrix.set_stuck_key_limit(1000, true);
+
+        sim.press(4, 4);
+        policy.scanned(&matrix.scan(&mut delay, 0).unwrap(), 0);
+        policy.scanned(&matrix.scan(&mut delay, 1000).unwrap(), 1000);
+        assert_eq!(policy.scanned(&matrix.scan(&mut delay, 1100).unwrap(), 1100), ScanMode::Idle);
+
+        // The stuck key alone does not wake the poll, another key does
+        assert!(policy.poll_due(1150));
+        assert_eq!(policy.polled(matrix.any_key_closed(&mut delay).unwrap(), 1150), ScanMode::Idle);
+
+        sim.press(0, 0);
+        assert_eq!(policy.polled(matrix.any_key_closed(&mut delay).unwrap(), 1200), ScanMode::Active);
+    }
+
+    #[test]
+    fn test_masked_stuck_key_wakes_once_released_and_pressed_again() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        let mut delay = SimDelay::default();
+        let mut policy = IdlePolicy::new(100, 50);
+        matrix.set_stuck_key_limit(1000, true);
+
+        sim.press(4, 4);
+        policy.scanned(&matrix.scan(&mut delay, 0).unwrap(), 0);
+        policy.scanned(&matrix.scan(&mut delay, 1000).unwrap(), 1000);
+        assert_eq!(policy.scanned(&matrix.scan(&mut delay, 1100).unwrap(), 1100), ScanMode::Idle);
+
+        sim.release(4, 4);
+        assert_eq!(policy.polled(matrix.any_key_closed(&mut delay).unwrap(), 1150), ScanMode::Idle);
+        assert!(matrix.diagnostics().stuck_keys().is_empty());
+
+        sim.press(4, 4);
+        assert_eq!(policy.polled(matrix.any_key_closed(&mut delay).unwrap(), 1200), ScanMode::Active);
+        assert!(matrix.scan(&mut delay, 1202).unwrap().pressed.contains(20));
+    }
+}
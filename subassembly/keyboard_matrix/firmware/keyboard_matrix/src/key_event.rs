@@ -0,0 +1,17 @@
+use crate::event_queue::EventQueue;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum KeyEventKind {
+    #[default]
+    Press,
+    Release,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KeyEvent {
+    pub key: u8,
+    pub kind: KeyEventKind,
+    pub timestamp_ms: u32,
+}
+
+pub type KeyEventQueue<const N: usize> = EventQueue<KeyEvent, N>;
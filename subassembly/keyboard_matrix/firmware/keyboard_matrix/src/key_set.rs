@@ -0,0 +1,221 @@
+use core::ops::{BitAnd, BitOr, Not, Sub};
+
+/// A set of key indices 0-31 stored as a bitmask, key 0 in the lowest bit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeySet(u32);
+
+impl KeySet {
+    pub const EMPTY: KeySet = KeySet(0);
+
+    /// Largest number of keys a set can hold.
+    pub const CAPACITY: usize = 32;
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(&self, key: usize) -> bool {
+        key < Self::CAPACITY && self.0 & (1 << key) != 0
+    }
+
+    /// Keys past `CAPACITY` cannot be held, they panic in debug builds and are dropped otherwise.
+    pub fn insert(&mut self, key: usize) {
+        debug_assert!(key < Self::CAPACITY, "key {} out of range", key);
+
+        if key < Self::CAPACITY {
+            self.0 |= 1 << key;
+        }
+    }
+
+    pub fn remove(&mut self, key: usize) {
+        if key < Self::CAPACITY {
+            self.0 &= !(1 << key);
+        }
+    }
+
+    pub fn set(&mut self, key: usize, present: bool) {
+        if present {
+            self.insert(key);
+        } else {
+            self.remove(key);
+        }
+    }
+
+    /// Number of keys in the set.
+    pub const fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn union(self, other: KeySet) -> KeySet {
+        KeySet(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: KeySet) -> KeySet {
+        KeySet(self.0 & other.0)
+    }
+
+    /// Keys in this set but not in `other`.
+    pub const fn difference(self, other: KeySet) -> KeySet {
+        KeySet(self.0 & !other.0)
+    }
+
+    /// Keys in ascending order.
+    pub fn iter(&self) -> KeySetIter {
+        KeySetIter(self.0)
+    }
+}
+
+impl<const N: usize> From<[bool; N]> for KeySet {
+    fn from(keys: [bool; N]) -> Self {
+        keys.iter()
+            .enumerate()
+            .filter(|(_, present)| **present)
+            .map(|(key, _)| key)
+            .collect()
+    }
+}
+
+impl FromIterator<usize> for KeySet {
+    fn from_iter<I: IntoIterator<Item = usize>>(keys: I) -> Self {
+        let mut set = KeySet::EMPTY;
+        for key in keys {
+            set.insert(key);
+        }
+        set
+    }
+}
+
+impl BitOr for KeySet {
+    type Output = KeySet;
+
+    fn bitor(self, other: KeySet) -> KeySet {
+        self.union(other)
+    }
+}
+
+impl BitAnd for KeySet {
+    type Output = KeySet;
+
+    fn bitand(self, other: KeySet) -> KeySet {
+        self.intersection(other)
+    }
+}
+
+impl Sub for KeySet {
+    type Output = KeySet;
+
+    fn sub(self, other: KeySet) -> KeySet {
+        self.difference(other)
+    }
+}
+
+/// Complement over all 32 possible keys.
+impl Not for KeySet {
+    type Output = KeySet;
+
+    fn not(self) -> KeySet {
+        KeySet(!self.0)
+    }
+}
+
+impl IntoIterator for KeySet {
+    type Item = usize;
+    type IntoIter = KeySetIter;
+
+    fn into_iter(self) -> KeySetIter {
+        self.iter()
+    }
+}
+
+pub struct KeySetIter(u32);
+
+impl Iterator for KeySetIter {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.0 == 0 {
+            return None;
+        }
+
+        let key = self.0.trailing_zeros() as usize;
+        self.0 &= self.0 - 1;
+
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove_contains() {
+        let mut set = KeySet::EMPTY;
+
+        set.insert(0);
+        set.insert(20);
+        set.set(31, true);
+        set.remove(0);
+
+        assert!(!set.contains(0));
+        assert!(set.contains(20));
+        assert!(set.contains(31));
+        assert!(!set.contains(32));
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_iterates_in_ascending_order() {
+        let set = KeySet::from_bits(0b1000_0000_0000_0000_0000_0000_0010_0101);
+
+        let mut keys = set.iter();
+        assert_eq!(keys.next(), Some(0));
+        assert_eq!(keys.next(), Some(2));
+        assert_eq!(keys.next(), Some(5));
+        assert_eq!(keys.next(), Some(31));
+        assert_eq!(keys.next(), None);
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let first: KeySet = [1, 2, 3].into_iter().collect();
+        let second: KeySet = [3, 4].into_iter().collect();
+
+        assert_eq!(first | second, [1, 2, 3, 4].into_iter().collect());
+        assert_eq!(first & second, [3].into_iter().collect());
+        assert_eq!(first - second, [1, 2].into_iter().collect());
+        assert!((first & !first).is_empty());
+    }
+
+    #[test]
+    fn test_from_bools() {
+        assert_eq!(KeySet::from([true, false, true]), KeySet::from_bits(0b101));
+    }
+
+    #[test]
+    fn test_remove_out_of_range_is_ignored() {
+        let mut set = KeySet::from_bits(u32::MAX);
+
+        set.remove(32);
+        set.remove(usize::MAX);
+
+        assert_eq!(set.bits(), u32::MAX);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn test_insert_out_of_range_panics_in_debug() {
+        let mut set = KeySet::EMPTY;
+
+        set.insert(32);
+    }
+}
@@ -1,76 +1,49 @@
-#[derive(Clone, Copy, Debug)]
-pub struct KeyboardState {
-    pub state: [bool; 21],
-    pub debounce_counter: [u8; 21],
-    pub pressed: [bool; 21],
-    pub released: [bool; 21],
-    pub depressed_count: u8,
-    pub pressed_count: u8,
-    pub released_count: u8,
+use crate::key_event::{KeyEvent, KeyEventKind, KeyEventQueue};
+use crate::key_set::KeySet;
+
+/// Key state for a matrix with `KEYS` logical keys, at most `KeySet::CAPACITY`.  Defaults to the 21 keys of
+/// the KIB.
+///
+/// `state` is the debounced state of each key, `pressed` and `released` the edges since the previous scan.
+/// `ghosted` marks keys whose reading was ambiguous in the latest scan, see `find_ghosts`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyboardState<const KEYS: usize = 21> {
+    pub state: KeySet,
+    pub pressed: KeySet,
+    pub released: KeySet,
+    pub ghosted: KeySet,
 }
 
-const DEBOUNCE_COUNTER: u8 = 100;
+impl<const KEYS: usize> KeyboardState<KEYS> {
+    const KEYS_FIT: () = assert!(KEYS <= KeySet::CAPACITY, "KeyboardState supports at most 32 keys");
+
+    /// Builds the state following this one from already debounced key readings.
+    pub fn build_new(&self, debounced_state: KeySet) -> Self {
+        let () = Self::KEYS_FIT;
 
-impl KeyboardState {
-    pub fn default() -> Self {
         Self {
-            state: [false; 21],
-            debounce_counter: [0; 21],
-            pressed: [false; 21],
-            released: [false; 21],
-            depressed_count: 0,
-            pressed_count: 0,
-            released_count: 0,
+            state: debounced_state,
+            pressed: debounced_state - self.state,
+            released: self.state - debounced_state,
+            ghosted: KeySet::EMPTY,
         }
     }
 
-    pub fn build_new(&self, new_state: [bool; 21]) -> Self {
-        let mut debounced_state: [bool; 21] = [false; 21];
-        let mut debounce_counter: [u8; 21] = self.debounce_counter;
-        let mut pressed: [bool; 21] = [false; 21];
-        let mut released: [bool; 21] = [false; 21];
-        let mut depressed_count = 0;
-        let mut pressed_count = 0;
-        let mut released_count = 0;
-
-        for i in 0..21 {
-            if new_state[i] != self.state[i] {
-                if debounce_counter[i] == 0 {
-                    debounced_state[i] = new_state[i];
-                    debounce_counter[i] = DEBOUNCE_COUNTER;
-                } else {
-                    debounced_state[i] = self.state[i];
-                }
+    /// Queues a `KeyEvent` for every key pressed or released in this state, in key order.
+    pub fn push_events<const N: usize>(&self, timestamp_ms: u32, queue: &mut KeyEventQueue<N>) {
+        for key in self.pressed | self.released {
+            let kind = if self.pressed.contains(key) {
+                KeyEventKind::Press
             } else {
-                debounced_state[i] = self.state[i];
-            }
-
-            if debounce_counter[i] > 0 {
-                debounce_counter[i] -= 1;
-            }
-            
-            if debounced_state[i] && !self.state[i] {
-                pressed[i] = true;
-                pressed_count += 1;
-            }
-            if !debounced_state[i] && self.state[i] {
-                released[i] = true;
-                released_count += 1;
-            }
-            if debounced_state[i] {
-                depressed_count += 1;
-            }
-        }
-
-        Self {
-            state: debounced_state,
-            debounce_counter: debounce_counter,
-            pressed: pressed,
-            released: released,
-
-            depressed_count: depressed_count,
-            pressed_count: pressed_count,
-            released_count: released_count,
+                KeyEventKind::Release
+            };
+
+            // Overflow is counted by the queue for the consumer to report
+            let _ = queue.push(KeyEvent {
+                key: key as u8,
+                kind,
+                timestamp_ms,
+            });
         }
     }
 }
@@ -80,173 +53,118 @@ mod tests {
     extern crate std;
     use super::*;
 
-    use more_asserts::*;
-
     #[test]
-    fn test_new_state_reflects_change_when_debounce_is_zero() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
+    fn test_new_state_reflects_change() {
+        let mut before_state: KeyboardState = KeyboardState::default();
+        before_state.state.set(0, false);
 
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
+        let mut new_state = KeySet::EMPTY;
+        new_state.set(0, true);
 
         let result = before_state.build_new(new_state);
 
-        assert_eq!(result.state[0], true);
+        assert!(result.state.contains(0));
     }
 
     #[test]
-    fn test_state_change_sets_debounce() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
-
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
-
-        let result = before_state.build_new(new_state);
-
-        assert_gt!(result.debounce_counter[0], 0);
-    }
-
-    #[test]
-    fn test_new_state_does_not_change_when_debounce_is_gt_zero() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 5;
-
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
-
-        let result = before_state.build_new(new_state);
-
-        assert_eq!(result.state[0], false);
-    }
-
-    #[test]
-    fn test_debounce_decrements_when_state_matches() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 5;
+    fn test_pressed_counter_reflects_newly_pressed_item() {
+        let mut before_state: KeyboardState = KeyboardState::default();
+        before_state.state.set(0, false);
 
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = false;
+        let mut new_state = KeySet::EMPTY;
+        new_state.set(0, true);
 
         let result = before_state.build_new(new_state);
 
-        assert_eq!(result.debounce_counter[0], 4);
+        assert_eq!(result.pressed.len(), 1);
     }
 
     #[test]
-    fn test_debounce_decrements_when_state_changes() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 5;
+    fn test_pressed_reflects_newly_pressed_item() {
+        let mut before_state: KeyboardState = KeyboardState::default();
+        before_state.state.set(0, false);
 
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
+        let mut new_state = KeySet::EMPTY;
+        new_state.set(0, true);
 
         let result = before_state.build_new(new_state);
 
-        assert_eq!(result.debounce_counter[0], 4);
+        assert!(result.pressed.contains(0));
     }
 
     #[test]
-    fn test_debounce_stays_zero_without_state_change() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
+    fn test_pressed_omits_previously_pressed_item() {
+        let mut before_state: KeyboardState = KeyboardState::default();
+        before_state.state.set(0, true);
 
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = false;
+        let mut new_state = KeySet::EMPTY;
+        new_state.set(0, true);
 
         let result = before_state.build_new(new_state);
 
-        assert_eq!(result.debounce_counter[0], 0);
+        assert!(!result.pressed.contains(0));
     }
 
     #[test]
-    fn test_pressed_counter_reflects_newly_pressed_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
+    fn test_released_counter_reflects_newly_released_item() {
+        let mut before_state: KeyboardState = KeyboardState::default();
+        before_state.state.set(0, true);
 
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
+        let mut new_state = KeySet::EMPTY;
+        new_state.set(0, false);
 
         let result = before_state.build_new(new_state);
 
-        assert_eq!(result.pressed_count, 1);
+        assert_eq!(result.released.len(), 1);
     }
 
     #[test]
-    fn test_pressed_reflects_newly_pressed_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
+    fn test_released_reflects_newly_released_item() {
+        let mut before_state: KeyboardState = KeyboardState::default();
+        before_state.state.set(0, true);
 
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
+        let mut new_state = KeySet::EMPTY;
+        new_state.set(0, false);
 
         let result = before_state.build_new(new_state);
 
-        assert_eq!(result.pressed[0], true);
+        assert!(result.released.contains(0));
     }
 
     #[test]
-    fn test_pressed_omits_previously_pressed_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = true;
-        before_state.debounce_counter[0] = 0;
+    fn test_preleased_omits_previously_released_item() {
+        let mut before_state: KeyboardState = KeyboardState::default();
+        before_state.state.set(0, false);
 
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
+        let mut new_state = KeySet::EMPTY;
+        new_state.set(0, false);
 
         let result = before_state.build_new(new_state);
 
-        assert_eq!(result.pressed[0], false);
+        assert!(!result.released.contains(0));
     }
 
     #[test]
-    fn test_released_counter_reflects_newly_released_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = true;
-        before_state.debounce_counter[0] = 0;
+    fn test_push_events_queues_presses_and_releases() {
+        let mut before_state: KeyboardState = KeyboardState::default();
+        before_state.state.set(3, true);
 
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = false;
+        let mut new_state = KeySet::EMPTY;
+        new_state.set(0, true);
 
         let result = before_state.build_new(new_state);
 
-        assert_eq!(result.released_count, 1);
-    }
-
-    #[test]
-    fn test_released_reflects_newly_released_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = true;
-        before_state.debounce_counter[0] = 0;
-
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = false;
+        let mut queue: KeyEventQueue<4> = KeyEventQueue::default();
+        result.push_events(42, &mut queue);
 
-        let result = before_state.build_new(new_state);
-
-        assert_eq!(result.released[0], true);
+        assert_eq!(queue.pop(), Some(KeyEvent { key: 0, kind: KeyEventKind::Press, timestamp_ms: 42 }));
+        assert_eq!(queue.pop(), Some(KeyEvent { key: 3, kind: KeyEventKind::Release, timestamp_ms: 42 }));
+        assert_eq!(queue.pop(), None);
     }
 
     #[test]
-    fn test_preleased_omits_previously_released_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
-
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = false;
-
-        let result = before_state.build_new(new_state);
-
-        assert_eq!(result.released[0], false);
+    fn test_kib_state_fits_in_sixteen_bytes() {
+        // Was three [bool; 21] arrays, a [u8; 21] array and three counts
+        assert_eq!(core::mem::size_of::<KeyboardState>(), 16);
     }
-}
\ No newline at end of file
+}
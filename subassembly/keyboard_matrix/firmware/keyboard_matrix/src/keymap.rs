@@ -0,0 +1,219 @@
+use crate::layout::KIB_KEYS;
+
+/// What a key does once its layers are resolved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyAction {
+    /// Key does nothing.
+    None,
+    /// Falls through to the next lower active layer.
+    Transparent,
+    /// Plays the note this many semitones above C of the selected octave.
+    Note(u8),
+    /// Selects octave 1-8.
+    Octave(u8),
+    /// Application defined function.
+    Function(u8),
+}
+
+/// Most layers a keymap can switch between.
+pub const MAX_LAYERS: usize = 8;
+
+/// Maps physical key indices to actions through a stack of layers.
+///
+/// Layer 0 is the base layer and is always active.  Higher layers are switched on and off at runtime, and
+/// the highest active layer with a non-transparent action for a key decides what it does.
+#[derive(Clone, Copy)]
+pub struct Keymap<const KEYS: usize = 21> {
+    layers: &'static [[KeyAction; KEYS]],
+    active_layers: u8,
+}
+
+impl<const KEYS: usize> Keymap<KEYS> {
+    /// `layers` holds at most `MAX_LAYERS` layers, any beyond that are ignored.
+    pub const fn new(layers: &'static [[KeyAction; KEYS]]) -> Self {
+        Self {
+            layers,
+            active_layers: 1,
+        }
+    }
+
+    pub fn action(&self, key: usize) -> KeyAction {
+        if key >= KEYS {
+            return KeyAction::None;
+        }
+
+        self.layers
+            .iter()
+            .enumerate()
+            .take(MAX_LAYERS)
+            .rev()
+            .filter(|(layer, _)| self.is_layer_active(*layer))
+            .map(|(_, actions)| actions[key])
+            .find(|action| *action != KeyAction::Transparent)
+            .unwrap_or(KeyAction::None)
+    }
+
+    /// The lowest key currently mapped to `action`.
+    pub fn key_for(&self, action: KeyAction) -> Option<usize> {
+        (0..KEYS).find(|key| self.action(*key) == action)
+    }
+
+    pub fn is_layer_active(&self, layer: usize) -> bool {
+        layer < MAX_LAYERS && self.active_layers & (1 << layer) != 0
+    }
+
+    /// Layers that do not exist and the base layer are left unchanged.
+    pub fn set_layer(&mut self, layer: usize, active: bool) {
+        if layer == 0 || layer >= self.layers.len().min(MAX_LAYERS) {
+            return;
+        }
+
+        if active {
+            self.active_layers |= 1 << layer;
+        } else {
+            self.active_layers &= !(1 << layer);
+        }
+    }
+
+    pub fn toggle_layer(&mut self, layer: usize) {
+        self.set_layer(layer, !self.is_layer_active(layer));
+    }
+
+    /// Bitmask of active layers, layer 0 in the lowest bit.
+    pub fn active_layers(&self) -> u8 {
+        self.active_layers
+    }
+
+    /// Replaces every active layer at once.  Layers that do not exist are dropped and the base layer kept.
+    pub fn set_active_layers(&mut self, layers: u8) {
+        let existing = match self.layers.len() {
+            count if count >= MAX_LAYERS => u8::MAX,
+            count => (1u8 << count) - 1,
+        };
+
+        self.active_layers = (layers & existing) | 1;
+    }
+}
+
+/// Keyboard Input Board keys: 0-7 select octaves 1-8, 8-20 play C to C an octave up.
+pub const KIB_BASE_LAYER: [KeyAction; KIB_KEYS] = [
+    KeyAction::Octave(1),
+    KeyAction::Octave(2),
+    KeyAction::Octave(3),
+    KeyAction::Octave(4),
+    KeyAction::Octave(5),
+    KeyAction::Octave(6),
+    KeyAction::Octave(7),
+    KeyAction::Octave(8),
+    KeyAction::Note(10), // A#
+    KeyAction::Note(8),  // G#
+    KeyAction::Note(6),  // F#
+    KeyAction::Note(3),  // D#
+    KeyAction::Note(1),  // C#
+    KeyAction::Note(0),  // C
+    KeyAction::Note(2),  // D
+    KeyAction::Note(4),  // E
+    KeyAction::Note(5),  // F
+    KeyAction::Note(7),  // G
+    KeyAction::Note(9),  // A
+    KeyAction::Note(11), // B
+    KeyAction::Note(12), // C
+];
+
+/// Keyboard Input Board function layer: keys 0-2 play functions 0-2 in place of octaves 1-3, the synth
+/// engine's sustain, loop and loop stop.
+pub const KIB_FUNCTION_LAYER: [KeyAction; KIB_KEYS] = {
+    let mut layer = [KeyAction::Transparent; KIB_KEYS];
+    layer[0] = KeyAction::Function(0);
+    layer[1] = KeyAction::Function(1);
+    layer[2] = KeyAction::Function(2);
+    layer
+};
+
+/// Base layer with the function layer above it as layer 1.
+pub const KIB_KEYMAP: Keymap<KIB_KEYS> = Keymap::new(&[KIB_BASE_LAYER, KIB_FUNCTION_LAYER]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T: KeyAction = KeyAction::Transparent;
+
+    static LAYERS: [[KeyAction; 3]; 3] = [
+        [KeyAction::Note(0), KeyAction::Note(1), KeyAction::Note(2)],
+        [KeyAction::Function(1), T, T],
+        [T, KeyAction::None, T],
+    ];
+
+    #[test]
+    fn test_base_layer_only_by_default() {
+        let keymap = Keymap::new(&LAYERS);
+
+        assert_eq!(keymap.action(0), KeyAction::Note(0));
+        assert_eq!(keymap.action(1), KeyAction::Note(1));
+        assert_eq!(keymap.active_layers(), 0b001);
+    }
+
+    #[test]
+    fn test_higher_layer_overrides_and_transparent_falls_through() {
+        let mut keymap = Keymap::new(&LAYERS);
+
+        keymap.set_layer(1, true);
+        keymap.set_layer(2, true);
+
+        assert_eq!(keymap.action(0), KeyAction::Function(1));
+        assert_eq!(keymap.action(1), KeyAction::None);
+        assert_eq!(keymap.action(2), KeyAction::Note(2));
+
+        keymap.toggle_layer(1);
+        assert_eq!(keymap.action(0), KeyAction::Note(0));
+    }
+
+    #[test]
+    fn test_base_and_missing_layers_cannot_change() {
+        let mut keymap = Keymap::new(&LAYERS);
+
+        keymap.set_layer(0, false);
+        keymap.set_layer(5, true);
+        assert_eq!(keymap.active_layers(), 0b001);
+
+        keymap.set_active_layers(0b1111_0110);
+        assert_eq!(keymap.active_layers(), 0b111);
+    }
+
+    #[test]
+    fn test_key_for_finds_resolved_action() {
+        let mut keymap = Keymap::new(&LAYERS);
+
+        assert_eq!(keymap.key_for(KeyAction::Note(0)), Some(0));
+
+        keymap.set_layer(1, true);
+        assert_eq!(keymap.key_for(KeyAction::Note(0)), None);
+        assert_eq!(keymap.key_for(KeyAction::Function(1)), Some(0));
+    }
+
+    #[test]
+    fn test_kib_keymap_covers_octaves_and_notes_once() {
+        for octave in 1..=8 {
+            assert_eq!(KIB_KEYMAP.key_for(KeyAction::Octave(octave)), Some(octave as usize - 1));
+        }
+
+        for offset in 0..=12 {
+            let key = KIB_KEYMAP.key_for(KeyAction::Note(offset)).unwrap();
+            assert!((8..KIB_KEYS).contains(&key), "Note {} on key {}", offset, key);
+        }
+    }
+
+    #[test]
+    fn test_kib_function_layer_covers_low_octaves() {
+        let mut keymap = KIB_KEYMAP;
+
+        keymap.set_active_layers(0b11);
+
+        for function in 0..3 {
+            assert_eq!(keymap.key_for(KeyAction::Function(function)), Some(function as usize));
+        }
+        assert_eq!(keymap.key_for(KeyAction::Octave(4)), Some(3));
+        assert_eq!(keymap.active_layers(), 0b11);
+    }
+}
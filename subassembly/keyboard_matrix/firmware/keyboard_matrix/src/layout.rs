@@ -0,0 +1,88 @@
+/// Marker for a row/column intersection with no switch fitted.
+pub const NO_KEY: u8 = 255;
+
+/// Maps each (row, column) intersection of a matrix to a logical key index.
+///
+/// `KEYS` is the number of logical keys the layout produces and sizes the resulting `KeyboardState`.
+pub struct MatrixLayout<const ROWS: usize, const COLS: usize, const KEYS: usize> {
+    pub key_index: [[u8; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize, const KEYS: usize> MatrixLayout<ROWS, COLS, KEYS> {
+    pub const fn new(key_index: [[u8; COLS]; ROWS]) -> Self {
+        Self { key_index }
+    }
+
+    /// The same layout indexed by (column, row).
+    pub const fn transposed(&self) -> [[u8; ROWS]; COLS] {
+        let mut key_index = [[NO_KEY; ROWS]; COLS];
+
+        let mut row = 0;
+        while row < ROWS {
+            let mut col = 0;
+            while col < COLS {
+                key_index[col][row] = self.key_index[row][col];
+                col += 1;
+            }
+            row += 1;
+        }
+
+        key_index
+    }
+
+    pub fn key_at(&self, row: usize, col: usize) -> Option<usize> {
+        match self.key_index[row][col] {
+            NO_KEY => None,
+            key => Some(key as usize),
+        }
+    }
+}
+
+pub const KIB_ROWS: usize = 5;
+pub const KIB_COLS: usize = 5;
+pub const KIB_KEYS: usize = 21;
+
+/// Keyboard Input Board wiring.  Rows are A-E, columns are M, N, O, P, Q.
+/// Keys 0-7 are the octave keys, 8-20 the note keys.
+pub const KIB_LAYOUT: MatrixLayout<KIB_ROWS, KIB_COLS, KIB_KEYS> = MatrixLayout::new([
+    [3, 2, 1, 0, NO_KEY],
+    [4, 5, 6, 7, NO_KEY],
+    [14, 11, 12, 13, NO_KEY],
+    [15, 17, 10, 16, NO_KEY],
+    [9, 18, 8, 19, 20],
+]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kib_layout_maps_every_key_once() {
+        let mut seen = [0u8; KIB_KEYS];
+
+        for row in 0..KIB_ROWS {
+            for col in 0..KIB_COLS {
+                if let Some(key) = KIB_LAYOUT.key_at(row, col) {
+                    seen[key] += 1;
+                }
+            }
+        }
+
+        for (key, count) in seen.iter().enumerate() {
+            assert_eq!(*count, 1, "Key {} appears {} times", key, count);
+        }
+    }
+
+    #[test]
+    fn test_transposed_swaps_rows_and_columns() {
+        let layout: MatrixLayout<2, 3, 5> = MatrixLayout::new([[0, 1, 2], [3, 4, NO_KEY]]);
+
+        assert_eq!(layout.transposed(), [[0, 3], [1, 4], [2, NO_KEY]]);
+    }
+
+    #[test]
+    fn test_no_key_is_none() {
+        assert_eq!(KIB_LAYOUT.key_at(0, 4), None);
+        assert_eq!(KIB_LAYOUT.key_at(4, 4), Some(20));
+    }
+}
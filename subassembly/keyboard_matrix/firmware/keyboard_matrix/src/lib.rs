@@ -1,126 +1,703 @@
 #![no_std]
 
+mod chord;
+mod debounce;
+mod diagnostics;
+mod event_queue;
+mod gesture;
+mod ghosting;
+mod idle;
+mod key_event;
+mod key_set;
 mod keyboard_state;
+mod keymap;
+mod layout;
+mod self_test;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
 
+pub use crate::chord::*;
+pub use crate::debounce::*;
+pub use crate::diagnostics::*;
+pub use crate::event_queue::EventQueue;
+pub use crate::gesture::*;
+pub use crate::ghosting::find_ghosts;
+pub use crate::idle::*;
+pub use crate::key_event::*;
+pub use crate::key_set::{KeySet, KeySetIter};
 pub use crate::keyboard_state::KeyboardState;
+pub use crate::keymap::*;
+pub use crate::layout::*;
+pub use crate::self_test::SelfTestReport;
 use embedded_hal::digital::v2::{InputPin, OutputPin};
 use embedded_hal::blocking::delay::DelayUs;
 
-pub struct KeyboardMatrix<ROWA, ROWB, ROWC, ROWD, ROWE, COLM, COLN, COLO, COLP, COLQ> {
-    row_a: ROWA,
-    row_b: ROWB,
-    row_c: ROWC,
-    row_d: ROWD,
-    row_e: ROWE,
+/// Electrical level that marks a line as active.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Polarity {
+    /// Lines are driven high to scan and inputs are pulled down, a closed switch reads high.
+    ActiveHigh,
+    /// Lines are driven low to scan and inputs are pulled up, a closed switch reads low.
+    ActiveLow,
+}
+
+/// Scans a switch matrix by activating one drive line at a time and reading every sense line.
+///
+/// Drive lines are the rows on boards with row-to-column diodes (`new`) and the columns on boards with
+/// column-to-row diodes (`new_col_to_row`).  Either way the layout is given in physical row/column order.
+pub struct KeyboardMatrix<OUT, IN, DEBOUNCER, const DRIVE: usize, const SENSE: usize, const KEYS: usize> {
+    drive: [OUT; DRIVE],
+    sense: [IN; SENSE],
 
-    col_n: COLN,
-    col_m: COLM,
-    col_o: COLO,
-    col_p: COLP,
-    col_q: COLQ,
+    key_index: [[u8; SENSE]; DRIVE],
+    polarity: Polarity,
+    lines_idle: bool,
+    debouncer: DEBOUNCER,
+    suppress_ghosts: bool,
+    diagnostics: Diagnostics<KEYS>,
 
-    keyboard_state: KeyboardState,
+    keyboard_state: KeyboardState<KEYS>,
+    events: KeyEventQueue<KEY_EVENT_QUEUE_SIZE>,
 }
 
 const SETTLE_DELAY_US: u16 = 1;
+pub const KEY_EVENT_QUEUE_SIZE: usize = 16;
+
+/// A pin error encountered while scanning.  The scan is abandoned and the keyboard state left unchanged.
+///
+/// Lines are numbered by their index in the drive or sense pin array.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScanError<E> {
+    /// Setting or reading back the level of drive `line` failed.
+    Drive { line: usize, error: E },
+    /// Reading sense `line` failed while drive line `driven` was active.  `None` if every drive line was
+    /// idle, or every drive line was active.
+    Sense { line: usize, driven: Option<usize>, error: E },
+}
 
-impl<ROWA, ROWB, ROWC, ROWD, ROWE, COLM, COLN, COLO, COLP, COLQ>
-    KeyboardMatrix<ROWA, ROWB, ROWC, ROWD, ROWE, COLM, COLN, COLO, COLP, COLQ>
+impl<OUT, IN, DEBOUNCER, E, const DRIVE: usize, const SENSE: usize, const KEYS: usize>
+    KeyboardMatrix<OUT, IN, DEBOUNCER, DRIVE, SENSE, KEYS>
 where
-    ROWA: OutputPin,
-    ROWB: OutputPin,
-    ROWC: OutputPin,
-    ROWD: OutputPin,
-    ROWE: OutputPin,
-    COLM: InputPin,
-    COLN: InputPin,
-    COLO: InputPin,
-    COLP: InputPin,
-    COLQ: InputPin,
+    OUT: OutputPin<Error = E>,
+    IN: InputPin<Error = E>,
+    DEBOUNCER: Debouncer<KEYS>,
 {
+    /// Creates a matrix for a board with diodes from rows to columns.  Rows are driven, columns read.
     pub fn new(
-        row_a: ROWA,
-        row_b: ROWB,
-        row_c: ROWC,
-        row_d: ROWD,
-        row_e: ROWE,
-
-        col_n: COLN,
-        col_m: COLM,
-        col_o: COLO,
-        col_p: COLP,
-        col_q: COLQ,
+        rows: [OUT; DRIVE],
+        cols: [IN; SENSE],
+        layout: &MatrixLayout<DRIVE, SENSE, KEYS>,
+        debouncer: DEBOUNCER,
+    ) -> Self {
+        Self::build(rows, cols, layout.key_index, debouncer)
+    }
+
+    /// Creates a matrix for a board with diodes from columns to rows.  Columns are driven, rows read.
+    pub fn new_col_to_row(
+        rows: [IN; SENSE],
+        cols: [OUT; DRIVE],
+        layout: &MatrixLayout<SENSE, DRIVE, KEYS>,
+        debouncer: DEBOUNCER,
+    ) -> Self {
+        Self::build(cols, rows, layout.transposed(), debouncer)
+    }
+
+    fn build(
+        drive: [OUT; DRIVE],
+        sense: [IN; SENSE],
+        key_index: [[u8; SENSE]; DRIVE],
+        debouncer: DEBOUNCER,
     ) -> Self {
         Self {
-            row_a,
-            row_b,
-            row_c,
-            row_d,
-            row_e,
-
-            col_n,
-            col_m,
-            col_o,
-            col_p,
-            col_q,
+            drive,
+            sense,
+
+            key_index,
+            polarity: Polarity::ActiveHigh,
+            lines_idle: false,
+            debouncer,
+            suppress_ghosts: false,
+            diagnostics: Diagnostics::new(),
 
             keyboard_state: KeyboardState::default(),
+            events: KeyEventQueue::default(),
         }
     }
 
-    
+    /// Defaults to `Polarity::ActiveHigh`.  Input pull resistors must be configured to match.
+    pub fn set_polarity(&mut self, polarity: Polarity) {
+        self.polarity = polarity;
+        self.lines_idle = false;
+    }
 
-    pub fn scan(&mut self, delay: &mut dyn DelayUs<u16>) -> KeyboardState {
-        let mut keystate: [bool; 21] = [false; 21];
+    /// Ambiguous keys are always flagged in `KeyboardState::ghosted`.  When suppression is enabled they
+    /// also hold their previous state until the ambiguity clears, which boards without per-key diodes need.
+    pub fn set_ghost_suppression(&mut self, enabled: bool) {
+        self.suppress_ghosts = enabled;
+    }
 
-        self.row_a.set_high().ok();
+    /// Keys held for `limit_ms` are reported as stuck in the diagnostics, zero disables detection.  When
+    /// `mask` is set stuck keys are released until they are physically released.
+    pub fn set_stuck_key_limit(&mut self, limit_ms: u32, mask: bool) {
+        self.diagnostics.set_stuck_limit(limit_ms, mask);
+    }
 
-        keystate[0] = self.col_p.is_high().ok().unwrap();
-        keystate[1] = self.col_o.is_high().ok().unwrap();
-        keystate[2] = self.col_n.is_high().ok().unwrap();
-        keystate[3] = self.col_m.is_high().ok().unwrap();
+    /// Per key bounce, rejection and hold statistics.
+    pub fn diagnostics(&mut self) -> &mut Diagnostics<KEYS> {
+        &mut self.diagnostics
+    }
 
-        self.row_a.set_low().ok();
-        delay.delay_us(SETTLE_DELAY_US);
-        self.row_b.set_high().ok();
+    fn key_at(&self, drive: usize, sense: usize) -> Option<usize> {
+        match self.key_index[drive][sense] {
+            NO_KEY => None,
+            key => Some(key as usize),
+        }
+    }
 
-        keystate[4] = self.col_m.is_high().ok().unwrap();
-        keystate[5] = self.col_n.is_high().ok().unwrap();
-        keystate[6] = self.col_o.is_high().ok().unwrap();
-        keystate[7] = self.col_p.is_high().ok().unwrap();
+    fn set_line(polarity: Polarity, pin: &mut OUT, active: bool) -> Result<(), E> {
+        if active == (polarity == Polarity::ActiveHigh) {
+            pin.set_high()
+        } else {
+            pin.set_low()
+        }
+    }
 
-        self.row_b.set_low().ok();
-        delay.delay_us(SETTLE_DELAY_US);
-        self.row_c.set_high().ok();
+    fn read_line<PIN: InputPin<Error = E>>(polarity: Polarity, pin: &PIN) -> Result<bool, E> {
+        match polarity {
+            Polarity::ActiveHigh => pin.is_high(),
+            Polarity::ActiveLow => pin.is_low(),
+        }
+    }
 
-        keystate[11] = self.col_n.is_high().ok().unwrap();
-        keystate[12] = self.col_o.is_high().ok().unwrap();
-        keystate[13] = self.col_p.is_high().ok().unwrap();
-        keystate[14] = self.col_m.is_high().ok().unwrap();
+    fn read_matrix(&mut self, delay: &mut dyn DelayUs<u16>) -> Result<[[bool; SENSE]; DRIVE], ScanError<E>> {
+        let polarity = self.polarity;
 
-        self.row_c.set_low().ok();
-        delay.delay_us(SETTLE_DELAY_US);
-        self.row_d.set_high().ok();
+        if !self.lines_idle {
+            for (line, pin) in self.drive.iter_mut().enumerate() {
+                Self::set_line(polarity, pin, false).map_err(|error| ScanError::Drive { line, error })?;
+            }
+
+            self.lines_idle = true;
+        }
+
+        let mut closed: [[bool; SENSE]; DRIVE] = [[false; SENSE]; DRIVE];
+
+        for (driven, drive_pin) in self.drive.iter_mut().enumerate() {
+            if driven > 0 {
+                delay.delay_us(SETTLE_DELAY_US);
+            }
 
-        keystate[10] = self.col_o.is_high().ok().unwrap();
-        keystate[15] = self.col_m.is_high().ok().unwrap();
-        keystate[16] = self.col_p.is_high().ok().unwrap();
-        keystate[17] = self.col_n.is_high().ok().unwrap();
+            Self::set_line(polarity, drive_pin, true)
+                .map_err(|error| ScanError::Drive { line: driven, error })?;
+
+            for (line, sense_pin) in self.sense.iter().enumerate() {
+                if self.key_index[driven][line] == NO_KEY {
+                    continue;
+                }
+
+                match Self::read_line(polarity, sense_pin) {
+                    Ok(active) => closed[driven][line] = active,
+                    Err(error) => {
+                        // Best effort to leave the line idle, the sense error is the one worth reporting
+                        Self::set_line(polarity, drive_pin, false).ok();
+
+                        return Err(ScanError::Sense { line, driven: Some(driven), error });
+                    }
+                }
+            }
+
+            Self::set_line(polarity, drive_pin, false)
+                .map_err(|error| ScanError::Drive { line: driven, error })?;
+        }
+
+        Ok(closed)
+    }
+
+    /// Activates every drive line at once and reports whether any sense line reads a closed switch.  Much
+    /// cheaper than `scan` for waking from idle, but leaves the debounced keyboard state untouched.
+    ///
+    /// Stuck keys being masked are left out, reading each key separately while there are any.  Masked keys
+    /// found open are no longer stuck, so pressing them again wakes the next poll.
+    pub fn any_key_closed(&mut self, delay: &mut dyn DelayUs<u16>) -> Result<bool, ScanError<E>> {
+        let polarity = self.polarity;
+
+        let masked = self.diagnostics.masked_keys();
+        if !masked.is_empty() {
+            // A stuck key holds its sense line active, which would wake every poll
+            let closed = self.read_matrix(delay)?;
+            let mut closed_keys = KeySet::EMPTY;
+
+            for (drive, closed) in closed.iter().enumerate() {
+                for (sense, closed) in closed.iter().enumerate() {
+                    if let Some(key) = self.key_at(drive, sense) {
+                        closed_keys.set(key, *closed);
+                    }
+                }
+            }
+
+            self.diagnostics.clear_stuck(masked - closed_keys);
+
+            return Ok(!(closed_keys - masked).is_empty());
+        }
+
+        // Cleared first so a drive error part way leaves the lines to be idled by the next scan
+        self.lines_idle = false;
+        for (line, pin) in self.drive.iter_mut().enumerate() {
+            Self::set_line(polarity, pin, true).map_err(|error| ScanError::Drive { line, error })?;
+        }
 
-        self.row_d.set_low().ok();
         delay.delay_us(SETTLE_DELAY_US);
-        self.row_e.set_high().ok();
 
-        keystate[8] = self.col_o.is_high().ok().unwrap();
-        keystate[9] = self.col_m.is_high().ok().unwrap();
-        keystate[18] = self.col_n.is_high().ok().unwrap();
-        keystate[19] = self.col_p.is_high().ok().unwrap();
-        keystate[20] = self.col_q.is_high().ok().unwrap();
+        let mut any_closed = Ok(false);
+        for (line, pin) in self.sense.iter().enumerate() {
+            match Self::read_line(polarity, pin) {
+                Ok(true) => {
+                    any_closed = Ok(true);
+                    break;
+                }
+                Ok(false) => {}
+                Err(error) => {
+                    any_closed = Err(ScanError::Sense { line, driven: None, error });
+                    break;
+                }
+            }
+        }
+
+        for (line, pin) in self.drive.iter_mut().enumerate() {
+            Self::set_line(polarity, pin, false).map_err(|error| ScanError::Drive { line, error })?;
+        }
+        self.lines_idle = true;
+
+        any_closed
+    }
+
+    /// Scans the matrix.  `now_ms` is a free running millisecond timestamp used for debouncing.
+    pub fn scan(
+        &mut self,
+        delay: &mut dyn DelayUs<u16>,
+        now_ms: u32,
+    ) -> Result<KeyboardState<KEYS>, ScanError<E>> {
+        let closed = self.read_matrix(delay).inspect_err(|_| {
+            self.diagnostics.scan_errors = self.diagnostics.scan_errors.saturating_add(1);
+        })?;
+
+        let ambiguous = find_ghosts(&closed);
+
+        let mut keystate: [bool; KEYS] = [false; KEYS];
+        let mut ghosted = KeySet::EMPTY;
+
+        for drive in 0..DRIVE {
+            for sense in 0..SENSE {
+                if let Some(key) = self.key_at(drive, sense) {
+                    if ambiguous[drive][sense] {
+                        ghosted.insert(key);
+                    }
+
+                    keystate[key] = if ambiguous[drive][sense] && self.suppress_ghosts {
+                        self.keyboard_state.state.contains(key)
+                    } else {
+                        closed[drive][sense]
+                    };
+                }
+            }
+        }
+
+        let debounced = self.debouncer.debounce(&keystate, now_ms);
+        let debounced = self.diagnostics.update(&keystate, &debounced, now_ms);
+
+        self.keyboard_state = self.keyboard_state.build_new(KeySet::from(debounced));
+        self.keyboard_state.ghosted = ghosted;
+        self.keyboard_state.push_events(now_ms, &mut self.events);
+
+        Ok(self.keyboard_state)
+    }
+
+    /// Press and release events from previous scans, oldest first.  Drain this every loop to avoid overflow.
+    pub fn events(&mut self) -> &mut KeyEventQueue<KEY_EVENT_QUEUE_SIZE> {
+        &mut self.events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimDelay, SimMatrix, SimPinError};
+
+    #[test]
+    fn test_scan_with_no_keys_pressed_reports_none() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        let result = matrix.scan(&mut SimDelay::default(), 0).unwrap();
+
+        assert_eq!(result.state.len(), 0);
+    }
+
+    #[test]
+    fn test_scan_maps_every_switch_to_its_layout_index() {
+        for row in 0..KIB_ROWS {
+            for col in 0..KIB_COLS {
+                let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+                let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+                sim.press(row, col);
+
+                let result = matrix.scan(&mut SimDelay::default(), 0).unwrap();
+
+                match KIB_LAYOUT.key_at(row, col) {
+                    Some(key) => {
+                        assert!(result.state.contains(key), "Switch {},{} should set key {}", row, col, key);
+                        assert_eq!(result.state.len(), 1);
+                    }
+                    None => assert_eq!(result.state.len(), 0),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_drives_rows_in_order_one_at_a_time() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        matrix.scan(&mut SimDelay::default(), 0).unwrap();
+
+        let (drive_log, drive_log_size) = sim.drive_log();
+        assert_eq!(&drive_log[..drive_log_size], &[0, 1, 2, 3, 4]);
+        assert_eq!(sim.overlapping_reads(), 0);
+        assert_eq!(sim.undriven_reads(), 0);
+    }
+
+    #[test]
+    fn test_scan_reads_only_populated_switches() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        matrix.scan(&mut SimDelay::default(), 0).unwrap();
+
+        for row in 0..KIB_ROWS {
+            for col in 0..KIB_COLS {
+                let expected = if KIB_LAYOUT.key_at(row, col).is_some() { 1 } else { 0 };
+                assert_eq!(sim.reads(row, col), expected, "Switch {},{}", row, col);
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_settles_between_rows() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        let mut delay = SimDelay::default();
+
+        matrix.scan(&mut delay, 0).unwrap();
+
+        assert_eq!(delay.total_us, (KIB_ROWS as u32 - 1) * SETTLE_DELAY_US as u32);
+    }
+
+    #[test]
+    fn test_scan_debounces_bouncing_switch() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        let mut delay = SimDelay::default();
+
+        // Row E, column Q is key 20
+        sim.press(4, 4);
+        matrix.scan(&mut delay, 0).unwrap();
+        sim.bounce(4, 4, 3);
+
+        for now_ms in 1..4 {
+            let result = matrix.scan(&mut delay, now_ms).unwrap();
+            assert!(result.state.contains(20));
+            assert_eq!(result.released.len(), 0);
+        }
+    }
+
+    #[test]
+    fn test_scan_without_diodes_reports_ghost_key() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::without_diodes();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.press(1, 0);
+        sim.press(1, 1);
+        sim.press(2, 0);
+
+        let result = matrix.scan(&mut SimDelay::default(), 0).unwrap();
+
+        // Keys 4, 5 and 14 are pressed, key 11 closes the rectangle
+        assert!(result.state.contains(11));
+        assert_eq!(result.state.len(), 4);
+    }
+
+    #[test]
+    fn test_scan_queues_timestamped_events() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        let mut delay = SimDelay::default();
+
+        sim.press(4, 4);
+        matrix.scan(&mut delay, 100).unwrap();
+        sim.release(4, 4);
+        matrix.scan(&mut delay, 110).unwrap();
+
+        let events = matrix.events();
+        assert_eq!(events.pop(), Some(KeyEvent { key: 20, kind: KeyEventKind::Press, timestamp_ms: 100 }));
+        assert_eq!(events.pop(), Some(KeyEvent { key: 20, kind: KeyEventKind::Release, timestamp_ms: 110 }));
+        assert_eq!(events.pop(), None);
+        assert!(!events.overflowed());
+    }
+
+    #[test]
+    fn test_scan_flags_ghost_rectangle() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::without_diodes();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.press(1, 0);
+        sim.press(1, 1);
+        sim.press(2, 0);
+
+        let result = matrix.scan(&mut SimDelay::default(), 0).unwrap();
+
+        for key in [4, 5, 11, 14] {
+            assert!(result.ghosted.contains(key), "Key {} should be ghosted", key);
+        }
+        assert_eq!(result.ghosted.len(), 4);
+    }
+
+    #[test]
+    fn test_scan_suppresses_new_ghosted_keys() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::without_diodes();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        matrix.set_ghost_suppression(true);
+
+        sim.press(1, 0);
+        sim.press(1, 1);
+        let result = matrix.scan(&mut SimDelay::default(), 0).unwrap();
+        assert!(result.state.contains(4) && result.state.contains(5));
+
+        sim.press(2, 0);
+        let result = matrix.scan(&mut SimDelay::default(), 10).unwrap();
+
+        // Keys already held stay held, neither the real press nor the ghost is reported
+        assert!(result.state.contains(4) && result.state.contains(5));
+        assert!(!result.state.contains(11));
+        assert!(!result.state.contains(14));
+        assert_eq!(result.pressed.len(), 0);
+
+        sim.release(1, 1);
+        let result = matrix.scan(&mut SimDelay::default(), 20).unwrap();
+
+        assert!(result.state.contains(14));
+        assert!(!result.state.contains(11));
+        assert_eq!(result.ghosted.len(), 0);
+    }
+
+    #[test]
+    fn test_scan_does_not_flag_diagonal_chord() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::without_diodes();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.press(0, 0);
+        sim.press(1, 1);
+        sim.press(2, 2);
+
+        let result = matrix.scan(&mut SimDelay::default(), 0).unwrap();
+
+        assert_eq!(result.ghosted.len(), 0);
+        assert_eq!(result.state.len(), 3);
+    }
+
+    #[test]
+    fn test_scan_reports_sense_error_and_idles_drive_line() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.press(0, 0);
+        sim.fail_col(2);
+
+        let result = matrix.scan(&mut SimDelay::default(), 0);
+
+        assert_eq!(result.unwrap_err(), ScanError::Sense { line: 2, driven: Some(0), error: SimPinError });
+        assert!(!sim.is_row_active(0));
+        assert!(matrix.events().is_empty());
+    }
+
+    #[test]
+    fn test_scan_reports_drive_error() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.fail_row(3);
+
+        let result = matrix.scan(&mut SimDelay::default(), 0);
+
+        assert_eq!(result.unwrap_err(), ScanError::Drive { line: 3, error: SimPinError });
+    }
+
+    #[test]
+    fn test_scan_after_error_keeps_previous_state() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.press(4, 4);
+        matrix.scan(&mut SimDelay::default(), 0).unwrap();
+
+        sim.fail_col(0);
+        assert!(matrix.scan(&mut SimDelay::default(), 10).is_err());
+        assert_eq!(matrix.diagnostics().scan_errors, 1);
+
+        sim.clear_failures();
+        let result = matrix.scan(&mut SimDelay::default(), 20).unwrap();
+
+        assert!(result.state.contains(20));
+        assert_eq!(result.pressed.len(), 0);
+    }
+
+    #[test]
+    fn test_active_low_scan() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        sim.set_polarity(Polarity::ActiveLow);
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        matrix.set_polarity(Polarity::ActiveLow);
+
+        let result = matrix.scan(&mut SimDelay::default(), 0).unwrap();
+        assert_eq!(result.state.len(), 0);
+
+        sim.press(4, 4);
+        let result = matrix.scan(&mut SimDelay::default(), 10).unwrap();
+
+        assert!(result.state.contains(20));
+        assert_eq!(result.state.len(), 1);
+        for row in 0..KIB_ROWS {
+            assert!(!sim.is_row_active(row), "Row {} left active", row);
+        }
+    }
+
+    #[test]
+    fn test_col_to_row_scan_drives_columns() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::col_to_row();
+        let mut matrix =
+            KeyboardMatrix::new_col_to_row(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        // Row C, column M is key 14
+        sim.press(2, 0);
+        let result = matrix.scan(&mut SimDelay::default(), 0).unwrap();
+
+        assert!(result.state.contains(14));
+        assert_eq!(result.state.len(), 1);
+
+        let (drive_log, drive_log_size) = sim.drive_log();
+        assert_eq!(&drive_log[..drive_log_size], &[0, 1, 2, 3, 4]);
+        assert_eq!(sim.overlapping_reads(), 0);
+    }
+
+    #[test]
+    fn test_col_to_row_scan_maps_every_switch_to_its_layout_index() {
+        for row in 0..KIB_ROWS {
+            for col in 0..KIB_COLS {
+                let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::col_to_row();
+                sim.set_polarity(Polarity::ActiveLow);
+                let mut matrix =
+                    KeyboardMatrix::new_col_to_row(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+                matrix.set_polarity(Polarity::ActiveLow);
+
+                sim.press(row, col);
+
+                let result = matrix.scan(&mut SimDelay::default(), 0).unwrap();
+
+                match KIB_LAYOUT.key_at(row, col) {
+                    Some(key) => assert!(result.state.contains(key), "Switch {},{} should set key {}", row, col, key),
+                    None => assert_eq!(result.state.len(), 0),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_records_rejected_bounce() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        let mut delay = SimDelay::default();
+
+        sim.press(4, 4);
+        matrix.scan(&mut delay, 0).unwrap();
+        sim.bounce(4, 4, 3);
+        matrix.scan(&mut delay, 1).unwrap();
+        matrix.scan(&mut delay, 2).unwrap();
+
+        let key = matrix.diagnostics().keys[20];
+        assert_eq!(key.transitions, 1);
+        assert_eq!(key.rejected, 1);
+        assert_eq!(key.held_ms, 2);
+    }
+
+    #[test]
+    fn test_scan_masks_stuck_key() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        let mut delay = SimDelay::default();
+        matrix.set_stuck_key_limit(1000, true);
+
+        sim.press(4, 4);
+        matrix.scan(&mut delay, 0).unwrap();
+        let result = matrix.scan(&mut delay, 1000).unwrap();
+
+        assert!(result.released.contains(20));
+        assert!(matrix.diagnostics().stuck_keys().contains(20));
+    }
+
+    #[test]
+    fn test_any_key_closed_drives_all_rows_together() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        assert!(!matrix.any_key_closed(&mut SimDelay::default()).unwrap());
+
+        sim.press(3, 2);
+
+        assert!(matrix.any_key_closed(&mut SimDelay::default()).unwrap());
+        assert_eq!(sim.undriven_reads(), 0);
+        for row in 0..KIB_ROWS {
+            assert!(!sim.is_row_active(row), "Row {} left active", row);
+        }
+    }
+
+    #[test]
+    fn test_any_key_closed_drive_error_leaves_lines_to_idle() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        let mut delay = SimDelay::default();
+
+        matrix.scan(&mut delay, 0).unwrap();
+
+        sim.fail_row(3);
+        assert!(matrix.any_key_closed(&mut delay).is_err());
+        assert!(sim.is_row_active(0));
+
+        sim.clear_failures();
+        sim.clear_log();
+        matrix.scan(&mut delay, 10).unwrap();
+
+        assert_eq!(sim.overlapping_reads(), 0);
+    }
+
+    #[test]
+    fn test_any_key_closed_leaves_state_for_next_scan() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        let mut policy = IdlePolicy::new(100, 50);
+        let mut delay = SimDelay::default();
 
-        self.row_e.set_low().ok();
+        let result = matrix.scan(&mut delay, 0).unwrap();
+        policy.scanned(&result, 0);
+        let result = matrix.scan(&mut delay, 100).unwrap();
+        assert_eq!(policy.scanned(&result, 100), ScanMode::Idle);
 
-        self.keyboard_state = self.keyboard_state.build_new(keystate);
+        sim.press(4, 4);
+        let closed = matrix.any_key_closed(&mut delay).unwrap();
+        assert_eq!(policy.polled(closed, 150), ScanMode::Active);
+        assert!(matrix.events().is_empty());
 
-        return self.keyboard_state;
+        let result = matrix.scan(&mut delay, 152).unwrap();
+        assert!(result.pressed.contains(20));
     }
 }
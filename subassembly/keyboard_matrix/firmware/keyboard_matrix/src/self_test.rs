@@ -0,0 +1,212 @@
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::{Debouncer, KeySet, KeyboardMatrix, ScanError, SETTLE_DELAY_US};
+
+/// Wiring faults found by `KeyboardMatrix::self_test`.  Line fields are bitmasks with line 0 in the lowest
+/// bit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SelfTestReport {
+    /// Drive lines that read the same closures as another drive line, i.e. shorted together.
+    pub shorted_drive: u32,
+    /// Sense lines that read active with every drive line idle.
+    pub stuck_sense: u32,
+    /// Keys that read closed while their drive line was the only one active, not counting stuck sense lines.
+    pub closed_keys: KeySet,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl<OUT, IN, DEBOUNCER, E, const DRIVE: usize, const SENSE: usize, const KEYS: usize>
+    KeyboardMatrix<OUT, IN, DEBOUNCER, DRIVE, SENSE, KEYS>
+where
+    OUT: OutputPin<Error = E>,
+    IN: InputPin<Error = E>,
+    DEBOUNCER: Debouncer<KEYS>,
+{
+    /// Checks the matrix wiring.  Every sense line is read with all drive lines idle, then again with each
+    /// drive line active alone.
+    ///
+    /// Keys should be released while the test runs.  A short between drive lines only reaches the sense
+    /// lines through a closed switch, so it is found when a key on either line is closed, for example by
+    /// holding one.  Supports up to 32 lines in each direction and 32 keys.
+    pub fn self_test(&mut self, delay: &mut dyn DelayUs<u16>) -> Result<SelfTestReport, ScanError<E>> {
+        let polarity = self.polarity;
+        let mut report = SelfTestReport::default();
+
+        for (line, pin) in self.drive.iter_mut().enumerate() {
+            Self::set_line(polarity, pin, false).map_err(|error| ScanError::Drive { line, error })?;
+        }
+        self.lines_idle = true;
+
+        delay.delay_us(SETTLE_DELAY_US);
+
+        for (line, pin) in self.sense.iter().enumerate() {
+            let active = Self::read_line(polarity, pin).map_err(|error| ScanError::Sense {
+                line,
+                driven: None,
+                error,
+            })?;
+
+            if active {
+                report.stuck_sense |= 1 << line;
+            }
+        }
+
+        // Sense lines reading active with each drive line active alone, stuck lines left out
+        let mut closures = [0u32; DRIVE];
+
+        for (driven, drive_pin) in self.drive.iter_mut().enumerate() {
+            Self::set_line(polarity, drive_pin, true)
+                .map_err(|error| ScanError::Drive { line: driven, error })?;
+
+            delay.delay_us(SETTLE_DELAY_US);
+
+            for (line, sense_pin) in self.sense.iter().enumerate() {
+                match Self::read_line(polarity, sense_pin) {
+                    Ok(active) => {
+                        if active && report.stuck_sense & 1 << line == 0 {
+                            closures[driven] |= 1 << line;
+                        }
+                    }
+                    Err(error) => {
+                        // Best effort to leave the line idle, the sense error is the one worth reporting
+                        Self::set_line(polarity, drive_pin, false).ok();
+
+                        return Err(ScanError::Sense { line, driven: Some(driven), error });
+                    }
+                }
+            }
+
+            Self::set_line(polarity, drive_pin, false)
+                .map_err(|error| ScanError::Drive { line: driven, error })?;
+        }
+
+        for driven in 0..DRIVE {
+            for line in 0..SENSE {
+                if closures[driven] & 1 << line != 0 {
+                    if let Some(key) = self.key_at(driven, line) {
+                        report.closed_keys.insert(key);
+                    }
+                }
+            }
+
+            // Shorted lines carry each other's closures, so both read the same sense lines
+            for other in driven + 1..DRIVE {
+                if closures[driven] != 0 && closures[driven] == closures[other] {
+                    report.shorted_drive |= 1 << driven | 1 << other;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimDelay, SimMatrix, SimPinError};
+    use crate::{EagerDebouncer, Polarity, KIB_COLS, KIB_LAYOUT, KIB_ROWS};
+
+    #[test]
+    fn test_healthy_matrix_passes() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        let report = matrix.self_test(&mut SimDelay::default()).unwrap();
+
+        assert!(report.passed());
+        for row in 0..KIB_ROWS {
+            assert!(!sim.is_row_active(row));
+        }
+    }
+
+    #[test]
+    fn test_reports_closed_keys() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.press(0, 0);
+        sim.press(4, 4);
+
+        let report = matrix.self_test(&mut SimDelay::default()).unwrap();
+
+        assert_eq!(report.closed_keys, KeySet::from_bits(1 << 3 | 1 << 20));
+        assert_eq!(report.shorted_drive, 0);
+        assert_eq!(report.stuck_sense, 0);
+    }
+
+    #[test]
+    fn test_detects_shorted_rows_through_a_closed_key() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.short_rows(1, 3);
+        sim.press(1, 2);
+
+        let report = matrix.self_test(&mut SimDelay::default()).unwrap();
+
+        assert_eq!(report.shorted_drive, 0b01010);
+        assert_eq!(report.stuck_sense, 0);
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn test_shorted_rows_without_a_closed_key_go_unseen() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.short_rows(1, 3);
+
+        assert!(matrix.self_test(&mut SimDelay::default()).unwrap().passed());
+    }
+
+    #[test]
+    fn test_detects_stuck_column_without_blaming_its_keys() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.stick_col(4);
+        sim.press(2, 1);
+
+        let report = matrix.self_test(&mut SimDelay::default()).unwrap();
+
+        assert_eq!(report.stuck_sense, 1 << 4);
+        assert_eq!(report.closed_keys.len(), 1);
+        assert_eq!(report.shorted_drive, 0);
+    }
+
+    #[test]
+    fn test_col_to_row_active_low_detects_shorted_columns() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::col_to_row();
+        sim.set_polarity(Polarity::ActiveLow);
+        let mut matrix =
+            KeyboardMatrix::new_col_to_row(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+        matrix.set_polarity(Polarity::ActiveLow);
+
+        assert!(matrix.self_test(&mut SimDelay::default()).unwrap().passed());
+
+        sim.short_cols(0, 2);
+        sim.press(1, 0);
+
+        assert_eq!(matrix.self_test(&mut SimDelay::default()).unwrap().shorted_drive, 0b00101);
+    }
+
+    #[test]
+    fn test_reports_idle_sense_error() {
+        let sim: SimMatrix<KIB_ROWS, KIB_COLS> = SimMatrix::new();
+        let mut matrix = KeyboardMatrix::new(sim.rows(), sim.cols(), &KIB_LAYOUT, EagerDebouncer::new(5));
+
+        sim.fail_col(1);
+
+        assert_eq!(
+            matrix.self_test(&mut SimDelay::default()),
+            Err(ScanError::Sense { line: 1, driven: None, error: SimPinError })
+        );
+    }
+}
@@ -0,0 +1,561 @@
+//! Host-side simulation of a switch matrix.
+//!
+//! `SimMatrix` models which switches are closed and the level each row and column line is driven to.  Pins
+//! borrowed from it implement the embedded-hal pin traits, as both outputs and inputs, so
+//! `KeyboardMatrix::scan` can be exercised without hardware in either diode direction and polarity.
+
+use core::cell::RefCell;
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::Polarity;
+
+const DRIVE_LOG_SIZE: usize = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Diodes {
+    RowToCol,
+    ColToRow,
+    None,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Line {
+    Row(usize),
+    Col(usize),
+}
+
+struct SimState<const ROWS: usize, const COLS: usize> {
+    closed: [[bool; COLS]; ROWS],
+    bounce_reads: [[u8; COLS]; ROWS],
+    row_level: [Option<bool>; ROWS],
+    col_level: [Option<bool>; COLS],
+    diodes: Diodes,
+    polarity: Polarity,
+    failed_rows: [bool; ROWS],
+    failed_cols: [bool; COLS],
+    shorted_rows: [[bool; ROWS]; ROWS],
+    shorted_cols: [[bool; COLS]; COLS],
+    stuck_rows: [bool; ROWS],
+    stuck_cols: [bool; COLS],
+
+    reads: [[u32; COLS]; ROWS],
+    overlapping_reads: u32,
+    undriven_reads: u32,
+    drive_log: [u8; DRIVE_LOG_SIZE],
+    drive_log_size: usize,
+}
+
+impl<const ROWS: usize, const COLS: usize> SimState<ROWS, COLS> {
+    fn active_level(&self) -> bool {
+        self.polarity == Polarity::ActiveHigh
+    }
+
+    fn row_active(&self, row: usize) -> bool {
+        self.row_level[row] == Some(self.active_level())
+    }
+
+    fn col_active(&self, col: usize) -> bool {
+        self.col_level[col] == Some(self.active_level())
+    }
+}
+
+pub struct SimMatrix<const ROWS: usize, const COLS: usize> {
+    state: RefCell<SimState<ROWS, COLS>>,
+}
+
+impl<const ROWS: usize, const COLS: usize> SimMatrix<ROWS, COLS> {
+    /// Creates an active high matrix with a diode from row to column on every switch, so closed switches
+    /// never interact.
+    pub fn new() -> Self {
+        Self {
+            state: RefCell::new(SimState {
+                closed: [[false; COLS]; ROWS],
+                bounce_reads: [[0; COLS]; ROWS],
+                row_level: [None; ROWS],
+                col_level: [None; COLS],
+                diodes: Diodes::RowToCol,
+                polarity: Polarity::ActiveHigh,
+                failed_rows: [false; ROWS],
+                failed_cols: [false; COLS],
+                shorted_rows: [[false; ROWS]; ROWS],
+                shorted_cols: [[false; COLS]; COLS],
+                stuck_rows: [false; ROWS],
+                stuck_cols: [false; COLS],
+
+                reads: [[0; COLS]; ROWS],
+                overlapping_reads: 0,
+                undriven_reads: 0,
+                drive_log: [0; DRIVE_LOG_SIZE],
+                drive_log_size: 0,
+            }),
+        }
+    }
+
+    /// Creates a matrix with a diode from column to row on every switch, so the columns must be driven.
+    pub fn col_to_row() -> Self {
+        let matrix = Self::new();
+        matrix.state.borrow_mut().diodes = Diodes::ColToRow;
+        matrix
+    }
+
+    /// Creates a matrix without per-switch diodes.  Current can flow backwards through closed switches,
+    /// so three closed corners of a rectangle make the fourth read as closed.
+    pub fn without_diodes() -> Self {
+        let matrix = Self::new();
+        matrix.state.borrow_mut().diodes = Diodes::None;
+        matrix
+    }
+
+    /// Lines that are not driven are pulled to the inactive level.
+    pub fn set_polarity(&self, polarity: Polarity) {
+        self.state.borrow_mut().polarity = polarity;
+    }
+
+    pub fn row(&self, row: usize) -> SimPin<'_, ROWS, COLS> {
+        SimPin {
+            matrix: self,
+            line: Line::Row(row),
+        }
+    }
+
+    pub fn col(&self, col: usize) -> SimPin<'_, ROWS, COLS> {
+        SimPin {
+            matrix: self,
+            line: Line::Col(col),
+        }
+    }
+
+    pub fn rows(&self) -> [SimPin<'_, ROWS, COLS>; ROWS] {
+        core::array::from_fn(|row| self.row(row))
+    }
+
+    pub fn cols(&self) -> [SimPin<'_, ROWS, COLS>; COLS] {
+        core::array::from_fn(|col| self.col(col))
+    }
+
+    pub fn set_switch(&self, row: usize, col: usize, closed: bool) {
+        self.state.borrow_mut().closed[row][col] = closed;
+    }
+
+    pub fn press(&self, row: usize, col: usize) {
+        self.set_switch(row, col, true);
+    }
+
+    pub fn release(&self, row: usize, col: usize) {
+        self.set_switch(row, col, false);
+    }
+
+    /// The switch alternates between open and closed for its next `reads` reads, starting from the
+    /// opposite of its modelled state, before settling.
+    pub fn bounce(&self, row: usize, col: usize, reads: u8) {
+        self.state.borrow_mut().bounce_reads[row][col] = reads;
+    }
+
+    /// Makes every subsequent use of `row` fail with `SimPinError`.
+    pub fn fail_row(&self, row: usize) {
+        self.state.borrow_mut().failed_rows[row] = true;
+    }
+
+    /// Makes every subsequent use of `col` fail with `SimPinError`.
+    pub fn fail_col(&self, col: usize) {
+        self.state.borrow_mut().failed_cols[col] = true;
+    }
+
+    pub fn clear_failures(&self) {
+        let mut state = self.state.borrow_mut();
+        state.failed_rows = [false; ROWS];
+        state.failed_cols = [false; COLS];
+    }
+
+    /// Connects two rows, so either being active makes both active.
+    pub fn short_rows(&self, first: usize, second: usize) {
+        let mut state = self.state.borrow_mut();
+        state.shorted_rows[first][second] = true;
+        state.shorted_rows[second][first] = true;
+    }
+
+    /// Connects two columns, so either being active makes both active.
+    pub fn short_cols(&self, first: usize, second: usize) {
+        let mut state = self.state.borrow_mut();
+        state.shorted_cols[first][second] = true;
+        state.shorted_cols[second][first] = true;
+    }
+
+    /// Makes every subsequent read of `row` return the active level.
+    pub fn stick_row(&self, row: usize) {
+        self.state.borrow_mut().stuck_rows[row] = true;
+    }
+
+    /// Makes every subsequent read of `col` return the active level.
+    pub fn stick_col(&self, col: usize) {
+        self.state.borrow_mut().stuck_cols[col] = true;
+    }
+
+    pub fn is_row_active(&self, row: usize) -> bool {
+        self.state.borrow().row_active(row)
+    }
+
+    pub fn is_col_active(&self, col: usize) -> bool {
+        self.state.borrow().col_active(col)
+    }
+
+    /// Number of reads through the switch made while its row or column was the only active line.
+    pub fn reads(&self, row: usize, col: usize) -> u32 {
+        self.state.borrow().reads[row][col]
+    }
+
+    /// Number of reads made while more than one line was active.
+    pub fn overlapping_reads(&self) -> u32 {
+        self.state.borrow().overlapping_reads
+    }
+
+    /// Number of reads made while no line was active.
+    pub fn undriven_reads(&self) -> u32 {
+        self.state.borrow().undriven_reads
+    }
+
+    /// Rows or columns in the order they were made active, up to the first 32.
+    pub fn drive_log(&self) -> ([u8; DRIVE_LOG_SIZE], usize) {
+        let state = self.state.borrow();
+        (state.drive_log, state.drive_log_size)
+    }
+
+    pub fn clear_log(&self) {
+        let mut state = self.state.borrow_mut();
+        state.reads = [[0; COLS]; ROWS];
+        state.overlapping_reads = 0;
+        state.undriven_reads = 0;
+        state.drive_log_size = 0;
+    }
+
+    fn drive(&self, line: Line, high: bool) -> Result<(), SimPinError> {
+        let mut state = self.state.borrow_mut();
+
+        let (index, failed, was_active) = match line {
+            Line::Row(row) => (row, state.failed_rows[row], state.row_active(row)),
+            Line::Col(col) => (col, state.failed_cols[col], state.col_active(col)),
+        };
+
+        if failed {
+            return Err(SimPinError);
+        }
+
+        if high == state.active_level() && !was_active && state.drive_log_size < DRIVE_LOG_SIZE {
+            let log_index = state.drive_log_size;
+            state.drive_log[log_index] = index as u8;
+            state.drive_log_size += 1;
+        }
+
+        match line {
+            Line::Row(row) => state.row_level[row] = Some(high),
+            Line::Col(col) => state.col_level[col] = Some(high),
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, line: Line) -> Result<bool, SimPinError> {
+        let mut state = self.state.borrow_mut();
+
+        let (failed, driven_level) = match line {
+            Line::Row(row) => (state.failed_rows[row], state.row_level[row]),
+            Line::Col(col) => (state.failed_cols[col], state.col_level[col]),
+        };
+
+        if failed {
+            return Err(SimPinError);
+        }
+
+        let stuck = match line {
+            Line::Row(row) => state.stuck_rows[row],
+            Line::Col(col) => state.stuck_cols[col],
+        };
+
+        if stuck {
+            return Ok(state.active_level());
+        }
+
+        let driven_rows: [bool; ROWS] = core::array::from_fn(|row| state.row_active(row));
+        let driven_cols: [bool; COLS] = core::array::from_fn(|col| state.col_active(col));
+
+        // Shorted lines follow whichever of them is active.
+        let active_rows: [bool; ROWS] = core::array::from_fn(|row| {
+            driven_rows[row] || (0..ROWS).any(|other| state.shorted_rows[row][other] && driven_rows[other])
+        });
+        let active_cols: [bool; COLS] = core::array::from_fn(|col| {
+            driven_cols[col] || (0..COLS).any(|other| state.shorted_cols[col][other] && driven_cols[other])
+        });
+
+        if let Some(level) = driven_level {
+            let shorted_active = match line {
+                Line::Row(row) => active_rows[row],
+                Line::Col(col) => active_cols[col],
+            };
+
+            return Ok(if shorted_active { state.active_level() } else { level });
+        }
+
+        let active_count = driven_rows.iter().chain(driven_cols.iter()).filter(|active| **active).count();
+        let only_row = driven_rows.iter().position(|active| *active);
+        let only_col = driven_cols.iter().position(|active| *active);
+        match (active_count, line, only_row, only_col) {
+            (0, ..) => state.undriven_reads += 1,
+            (1, Line::Col(col), Some(row), _) | (1, Line::Row(row), _, Some(col)) => state.reads[row][col] += 1,
+            (1, ..) => {}
+            _ => state.overlapping_reads += 1,
+        }
+
+        let mut contact = [[false; COLS]; ROWS];
+        for (row, contact_row) in contact.iter_mut().enumerate() {
+            for (col, contact) in contact_row.iter_mut().enumerate() {
+                *contact = state.closed[row][col];
+
+                if state.bounce_reads[row][col] > 0 {
+                    if state.bounce_reads[row][col] % 2 == 1 {
+                        *contact = !*contact;
+                    }
+
+                    let read_through = match line {
+                        Line::Col(read_col) => read_col == col && active_rows[row],
+                        Line::Row(read_row) => read_row == row && active_cols[col],
+                    };
+
+                    if read_through {
+                        state.bounce_reads[row][col] -= 1;
+                    }
+                }
+            }
+        }
+
+        let connected = match (state.diodes, line) {
+            (Diodes::RowToCol, Line::Col(col)) => (0..ROWS).any(|row| active_rows[row] && contact[row][col]),
+            (Diodes::ColToRow, Line::Row(row)) => (0..COLS).any(|col| active_cols[col] && contact[row][col]),
+            (Diodes::RowToCol, Line::Row(_)) | (Diodes::ColToRow, Line::Col(_)) => false,
+            (Diodes::None, _) => {
+                // Flood fill from the active lines through every closed switch.
+                let mut live_rows = active_rows;
+                let mut live_cols = active_cols;
+                let mut changed = true;
+
+                while changed {
+                    changed = false;
+                    for row in 0..ROWS {
+                        for col in 0..COLS {
+                            if contact[row][col] && live_rows[row] != live_cols[col] {
+                                live_rows[row] = true;
+                                live_cols[col] = true;
+                                changed = true;
+                            }
+                        }
+                    }
+                }
+
+                match line {
+                    Line::Row(row) => live_rows[row],
+                    Line::Col(col) => live_cols[col],
+                }
+            }
+        };
+
+        Ok(connected == state.active_level())
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> Default for SimMatrix<ROWS, COLS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Error returned by pins of a row or column set to fail.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimPinError;
+
+/// A row or column line.  Setting a level drives the line, reading an undriven line returns the active
+/// level if a closed switch connects it to an active line and the inactive level otherwise.
+pub struct SimPin<'a, const ROWS: usize, const COLS: usize> {
+    matrix: &'a SimMatrix<ROWS, COLS>,
+    line: Line,
+}
+
+impl<'a, const ROWS: usize, const COLS: usize> OutputPin for SimPin<'a, ROWS, COLS> {
+    type Error = SimPinError;
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.matrix.drive(self.line, true)
+    }
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.matrix.drive(self.line, false)
+    }
+}
+
+impl<'a, const ROWS: usize, const COLS: usize> InputPin for SimPin<'a, ROWS, COLS> {
+    type Error = SimPinError;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        self.matrix.read(self.line)
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        self.matrix.read(self.line).map(|high| !high)
+    }
+}
+
+/// Delay that returns immediately, keeping a running total of the time requested.
+#[derive(Default)]
+pub struct SimDelay {
+    pub total_us: u32,
+}
+
+impl DelayUs<u16> for SimDelay {
+    fn delay_us(&mut self, us: u16) {
+        self.total_us += us as u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_closed_switch_reads_high_only_on_its_row() {
+        let matrix: SimMatrix<2, 2> = SimMatrix::new();
+        let mut rows = matrix.rows();
+        let cols = matrix.cols();
+
+        matrix.press(1, 0);
+
+        rows[0].set_high().unwrap();
+        assert!(!cols[0].is_high().unwrap());
+        rows[0].set_low().unwrap();
+
+        rows[1].set_high().unwrap();
+        assert!(cols[0].is_high().unwrap());
+        assert!(!cols[1].is_high().unwrap());
+    }
+
+    #[test]
+    fn test_bounce_alternates_before_settling() {
+        let matrix: SimMatrix<1, 1> = SimMatrix::new();
+        let mut rows = matrix.rows();
+        let cols = matrix.cols();
+
+        matrix.press(0, 0);
+        matrix.bounce(0, 0, 3);
+
+        rows[0].set_high().unwrap();
+
+        assert!(!cols[0].is_high().unwrap());
+        assert!(cols[0].is_high().unwrap());
+        assert!(!cols[0].is_high().unwrap());
+        assert!(cols[0].is_high().unwrap());
+        assert!(cols[0].is_high().unwrap());
+    }
+
+    #[test]
+    fn test_ghost_appears_without_diodes() {
+        let matrix: SimMatrix<2, 2> = SimMatrix::without_diodes();
+        let mut rows = matrix.rows();
+        let cols = matrix.cols();
+
+        matrix.press(0, 0);
+        matrix.press(0, 1);
+        matrix.press(1, 0);
+
+        rows[1].set_high().unwrap();
+
+        assert!(cols[1].is_high().unwrap(), "Expected ghost at row 1, col 1");
+    }
+
+    #[test]
+    fn test_no_ghost_with_diodes() {
+        let matrix: SimMatrix<2, 2> = SimMatrix::new();
+        let mut rows = matrix.rows();
+        let cols = matrix.cols();
+
+        matrix.press(0, 0);
+        matrix.press(0, 1);
+        matrix.press(1, 0);
+
+        rows[1].set_high().unwrap();
+
+        assert!(!cols[1].is_high().unwrap());
+    }
+
+    #[test]
+    fn test_active_low_closed_switch_reads_low() {
+        let matrix: SimMatrix<2, 2> = SimMatrix::new();
+        matrix.set_polarity(Polarity::ActiveLow);
+        let mut rows = matrix.rows();
+        let cols = matrix.cols();
+
+        matrix.press(0, 1);
+
+        assert!(cols[1].is_high().unwrap(), "Undriven column should be pulled high");
+
+        rows[0].set_low().unwrap();
+        rows[1].set_high().unwrap();
+
+        assert!(cols[1].is_low().unwrap());
+        assert!(cols[0].is_high().unwrap());
+    }
+
+    #[test]
+    fn test_col_to_row_diode_only_conducts_from_column() {
+        let matrix: SimMatrix<2, 2> = SimMatrix::col_to_row();
+        let rows = matrix.rows();
+        let mut cols = matrix.cols();
+
+        matrix.press(1, 0);
+
+        cols[1].set_high().unwrap();
+        assert!(!rows[1].is_high().unwrap());
+        cols[1].set_low().unwrap();
+
+        cols[0].set_high().unwrap();
+        assert!(rows[1].is_high().unwrap());
+        assert!(!rows[0].is_high().unwrap());
+
+        let matrix: SimMatrix<2, 2> = SimMatrix::col_to_row();
+        let mut rows = matrix.rows();
+        let cols = matrix.cols();
+
+        matrix.press(1, 0);
+        rows[1].set_high().unwrap();
+
+        assert!(!cols[0].is_high().unwrap(), "Diode should block current from the row");
+    }
+
+    #[test]
+    fn test_shorted_rows_read_back_active() {
+        let matrix: SimMatrix<3, 1> = SimMatrix::new();
+        let mut rows = matrix.rows();
+        let cols = matrix.cols();
+
+        matrix.short_rows(0, 2);
+        matrix.press(2, 0);
+
+        rows[0].set_high().unwrap();
+        rows[1].set_low().unwrap();
+        rows[2].set_low().unwrap();
+
+        assert!(rows[2].is_high().unwrap());
+        assert!(!rows[1].is_high().unwrap());
+        assert!(cols[0].is_high().unwrap(), "Switch on the shorted row should conduct");
+    }
+
+    #[test]
+    fn test_stuck_col_reads_active_undriven() {
+        let matrix: SimMatrix<1, 2> = SimMatrix::new();
+        matrix.set_polarity(Polarity::ActiveLow);
+        let cols = matrix.cols();
+
+        matrix.stick_col(1);
+
+        assert!(cols[1].is_low().unwrap());
+        assert!(cols[0].is_high().unwrap());
+    }
+}
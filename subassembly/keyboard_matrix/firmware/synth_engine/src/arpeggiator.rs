@@ -0,0 +1,311 @@
+use crate::{NoteOrder, NoteSet, NUM_NOTES};
+
+/// Order the arpeggiator steps through the held notes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArpOrder {
+    Up,
+    Down,
+    /// Up then down without repeating the top and bottom notes.
+    UpDown,
+    Random,
+    /// Order first held, up to `NOTE_ORDER_SIZE` notes.
+    AsPlayed,
+}
+
+impl ArpOrder {
+    pub fn from_int(value: u8) -> Option<ArpOrder> {
+        match value {
+            0 => Some(ArpOrder::Up),
+            1 => Some(ArpOrder::Down),
+            2 => Some(ArpOrder::UpDown),
+            3 => Some(ArpOrder::Random),
+            4 => Some(ArpOrder::AsPlayed),
+            _ => None,
+        }
+    }
+
+    pub fn to_int(&self) -> u8 {
+        match self {
+            ArpOrder::Up => 0,
+            ArpOrder::Down => 1,
+            ArpOrder::UpDown => 2,
+            ArpOrder::Random => 3,
+            ArpOrder::AsPlayed => 4,
+        }
+    }
+}
+
+/// Plays the held notes one at a time in a timed sequence.
+///
+/// A step starts as soon as the first note is held, then every beat / `subdivision`.  Each step sounds for
+/// `gate_percent` of the step, at 100 repeated notes are tied instead of retriggered.
+pub struct Arpeggiator {
+    pub enabled: bool,
+    pub order: ArpOrder,
+    pub bpm: u16,
+    /// Steps per beat, 4 for sixteenth notes.
+    pub subdivision: u8,
+    /// 1 - 100
+    pub gate_percent: u8,
+    /// Held notes are repeated in this many octaves upwards, 1 - 4.
+    pub octaves: u8,
+
+    played: NoteOrder,
+    position: usize,
+    elapsed_ms: u32,
+    note: Option<u8>,
+    random: u32,
+}
+
+impl Default for Arpeggiator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Arpeggiator {
+    /// Disabled, upwards sixteenth notes at 120 BPM with a 50% gate over one octave.
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            order: ArpOrder::Up,
+            bpm: 120,
+            subdivision: 4,
+            gate_percent: 50,
+            octaves: 1,
+
+            played: NoteOrder::default(),
+            position: 0,
+            elapsed_ms: 0,
+            note: None,
+            random: 0x1D87_2B41,
+        }
+    }
+
+    pub fn step_ms(&self) -> u32 {
+        let steps_per_minute = self.bpm.max(1) as u32 * self.subdivision.max(1) as u32;
+
+        (60_000 / steps_per_minute).max(1)
+    }
+
+    fn gate_ms(&self) -> u32 {
+        self.step_ms() * self.gate_percent.clamp(1, 100) as u32 / 100
+    }
+
+    /// The note sounding now, empty between gates.
+    pub fn notes(&self) -> NoteSet {
+        let mut notes = NoteSet::EMPTY;
+
+        if let Some(note_index) = self.note {
+            if self.elapsed_ms < self.gate_ms() {
+                notes.insert(note_index as usize);
+            }
+        }
+
+        notes
+    }
+
+    /// Advances by `delta_t_ms` with `held` as the notes to arpeggiate, returning the notes to sound.
+    pub fn update(&mut self, delta_t_ms: u32, held: &NoteSet) -> NoteSet {
+        self.played.update(held);
+
+        if held.is_empty() {
+            self.position = 0;
+            self.elapsed_ms = 0;
+            self.note = None;
+
+            return NoteSet::EMPTY;
+        }
+
+        if self.note.is_none() {
+            // First note held, play straight away
+            self.elapsed_ms = 0;
+            self.note = self.next_note(held);
+        } else {
+            self.elapsed_ms += delta_t_ms;
+
+            let step_ms = self.step_ms();
+            while self.elapsed_ms >= step_ms {
+                self.elapsed_ms -= step_ms;
+                self.note = self.next_note(held);
+            }
+        }
+
+        self.notes()
+    }
+
+    fn next_note(&mut self, held: &NoteSet) -> Option<u8> {
+        let count = self.sequence_len(held);
+        if count == 0 {
+            return None;
+        }
+
+        let index = match self.order {
+            ArpOrder::Up | ArpOrder::AsPlayed => self.position % count,
+            ArpOrder::Down => count - 1 - self.position % count,
+            ArpOrder::UpDown if count == 1 => 0,
+            ArpOrder::UpDown => {
+                let cycle = self.position % (2 * count - 2);
+                if cycle < count {
+                    cycle
+                } else {
+                    2 * count - 2 - cycle
+                }
+            }
+            ArpOrder::Random => {
+                // xorshift32
+                self.random ^= self.random << 13;
+                self.random ^= self.random >> 17;
+                self.random ^= self.random << 5;
+                self.random as usize % count
+            }
+        };
+
+        self.position = self.position.wrapping_add(1);
+
+        self.sequence_note(held, index)
+    }
+
+    fn sequence_len(&self, held: &NoteSet) -> usize {
+        match self.order {
+            ArpOrder::AsPlayed => self.as_played().count(),
+            _ => self.ascending(held).iter().count(),
+        }
+    }
+
+    fn sequence_note(&self, held: &NoteSet, index: usize) -> Option<u8> {
+        match self.order {
+            ArpOrder::AsPlayed => self.as_played().nth(index),
+            _ => self.ascending(held).iter().nth(index).map(|note_index| note_index as u8),
+        }
+    }
+
+    /// Held notes and their octave copies in ascending order.
+    fn ascending(&self, held: &NoteSet) -> NoteSet {
+        let mut notes = NoteSet::EMPTY;
+
+        for octave in 0..self.octaves.clamp(1, 4) as usize {
+            for note_index in held.iter() {
+                notes.insert(note_index + octave * 12);
+            }
+        }
+
+        notes
+    }
+
+    /// Played notes in order, followed by each octave copy in the same order.
+    fn as_played(&self) -> impl Iterator<Item = u8> + '_ {
+        let played = self.played.notes();
+
+        (0..self.octaves.clamp(1, 4))
+            .flat_map(move |octave| played.iter().map(move |note| note + octave * 12))
+            .filter(|note_index| (*note_index as usize) < NUM_NOTES)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ArpOrder, Arpeggiator, NoteSet};
+
+    fn notes(note_indices: &[usize]) -> NoteSet {
+        let mut notes = NoteSet::EMPTY;
+        for note_index in note_indices {
+            notes.insert(*note_index);
+        }
+        notes
+    }
+
+    fn sequence(arpeggiator: &mut Arpeggiator, held: &NoteSet, steps: usize) -> [usize; 8] {
+        let mut played = [0; 8];
+        let step_ms = arpeggiator.step_ms();
+
+        for (step, played) in played.iter_mut().take(steps).enumerate() {
+            let delta_t_ms = if step == 0 { 0 } else { step_ms };
+            *played = arpeggiator.update(delta_t_ms, held).iter().next().unwrap();
+        }
+
+        played
+    }
+
+    #[test]
+    fn up_down_and_updown_orders() {
+        let held = notes(&[40, 36, 43]);
+
+        let mut arpeggiator = Arpeggiator::new();
+        assert_eq!(sequence(&mut arpeggiator, &held, 4)[..4], [36, 40, 43, 36]);
+
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.order = ArpOrder::Down;
+        assert_eq!(sequence(&mut arpeggiator, &held, 4)[..4], [43, 40, 36, 43]);
+
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.order = ArpOrder::UpDown;
+        assert_eq!(sequence(&mut arpeggiator, &held, 6)[..6], [36, 40, 43, 40, 36, 40]);
+    }
+
+    #[test]
+    fn as_played_follows_press_order() {
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.order = ArpOrder::AsPlayed;
+
+        arpeggiator.update(0, &notes(&[43]));
+        arpeggiator.update(0, &notes(&[43, 36]));
+        let held = notes(&[43, 36, 40]);
+        arpeggiator.update(0, &held);
+
+        let step_ms = arpeggiator.step_ms();
+        let mut played = [0; 3];
+        for note in played.iter_mut() {
+            *note = arpeggiator.update(step_ms, &held).iter().next().unwrap();
+        }
+
+        assert_eq!(played, [36, 40, 43]);
+    }
+
+    #[test]
+    fn octave_range_repeats_notes_higher() {
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.octaves = 2;
+
+        assert_eq!(sequence(&mut arpeggiator, &notes(&[36, 40]), 5)[..5], [36, 40, 48, 52, 36]);
+    }
+
+    #[test]
+    fn random_stays_within_held_notes() {
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.order = ArpOrder::Random;
+        let held = notes(&[36, 40, 43]);
+
+        for note in sequence(&mut arpeggiator, &held, 8) {
+            assert!(held.contains(note));
+        }
+    }
+
+    #[test]
+    fn rate_and_gate_timing() {
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.bpm = 100;
+        arpeggiator.subdivision = 2;
+        arpeggiator.gate_percent = 25;
+        let held = notes(&[36, 40]);
+
+        assert_eq!(arpeggiator.step_ms(), 300);
+
+        assert!(arpeggiator.update(0, &held).contains(36));
+        assert!(arpeggiator.update(74, &held).contains(36));
+        assert!(arpeggiator.update(1, &held).is_empty());
+        assert!(arpeggiator.update(224, &held).is_empty());
+        assert!(arpeggiator.update(1, &held).contains(40));
+    }
+
+    #[test]
+    fn releasing_all_notes_stops_and_restarts_from_first() {
+        let mut arpeggiator = Arpeggiator::new();
+        let held = notes(&[36, 40]);
+
+        sequence(&mut arpeggiator, &held, 2);
+
+        assert!(arpeggiator.update(10, &NoteSet::EMPTY).is_empty());
+        assert!(arpeggiator.update(0, &held).contains(36));
+    }
+}
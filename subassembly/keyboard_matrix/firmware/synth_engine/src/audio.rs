@@ -0,0 +1,389 @@
+use crate::{
+    midi_note_millihertz, Adsr, Envelope, EnvelopeRates, NoteState, Oscillator, SynthState, Waveform, NUM_NOTES,
+    Q15_ONE,
+};
+
+/// Voices the audio engine renders at once.
+pub const AUDIO_VOICES: usize = 4;
+
+#[derive(Clone, Copy, Default)]
+struct AudioVoice {
+    note_index: Option<u8>,
+    // Note held, the envelope is past its attack or sustaining
+    gate: bool,
+    // Note was Pressed when last seen, so one Pressed state only triggers once however many blocks it spans
+    pressed: bool,
+    oscillator: Oscillator,
+    envelope: Envelope,
+}
+
+/// Renders mono PCM for the notes a `SynthState` has active, for a DAC or PWM audio output.
+///
+/// Each voice is an oscillator through an ADSR envelope.  A note's envelope attacks when it is Pressed, holds
+/// while it sustains and releases once it reaches `NoteState::Release`.  Attack and release take
+/// `SynthState::attack_ms` and `release_ms`, so the release fades out as the note goes Off.  The voices are
+/// summed, scaled by `volume` and saturated to 16 bits.
+///
+/// A new note takes a free voice, or the quietest releasing one.  Voices of held notes are never stolen, so
+/// notes beyond `AUDIO_VOICES` wait for a voice to be released.
+pub struct AudioEngine {
+    pub waveform: Waveform,
+    /// Time to fall from full scale to silence, the decay to `sustain` takes its share of it.
+    pub decay_ms: u16,
+    /// Q15 level held while the note is held, 0 - 32768.
+    pub sustain: u16,
+    /// Q15 gain on the summed voices, 0 - 32768.
+    pub volume: u16,
+
+    sample_rate: u32,
+    voices: [AudioVoice; AUDIO_VOICES],
+}
+
+impl AudioEngine {
+    /// Saw with the default decay and sustain at half volume, leaving headroom for two voices at full scale.
+    pub fn new(sample_rate: u32) -> Self {
+        let adsr = Adsr::new();
+
+        Self {
+            waveform: Waveform::Saw,
+            decay_ms: adsr.decay_ms,
+            sustain: adsr.sustain,
+            volume: 16_384,
+
+            sample_rate: sample_rate.max(1),
+            voices: [AudioVoice::default(); AUDIO_VOICES],
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Voices playing or releasing a note.
+    pub fn sounding_voices(&self) -> usize {
+        self.voices.iter().filter(|voice| voice.note_index.is_some()).count()
+    }
+
+    /// Silences every voice straight away.
+    pub fn reset(&mut self) {
+        self.voices = [AudioVoice::default(); AUDIO_VOICES];
+    }
+
+    /// Fills `buffer` with the next samples for the notes active in `synth_state`.
+    pub fn render(&mut self, synth_state: &SynthState, buffer: &mut [i16]) {
+        self.follow_notes(synth_state);
+
+        let adsr = Adsr {
+            attack_ms: synth_state.attack_ms,
+            decay_ms: self.decay_ms,
+            sustain: self.sustain,
+            release_ms: synth_state.release_ms,
+        };
+        let rates = EnvelopeRates::new(&adsr, self.sample_rate);
+        let volume = (self.volume as i32).min(Q15_ONE) as i64;
+
+        for sample in buffer.iter_mut() {
+            let mut mix: i32 = 0;
+
+            for voice in self.voices.iter_mut().filter(|voice| voice.note_index.is_some()) {
+                let level = voice.envelope.next(&rates);
+                mix += (voice.oscillator.next(self.waveform) * level) >> 15;
+            }
+
+            *sample = ((mix as i64 * volume) >> 15).clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+        }
+
+        for voice in self.voices.iter_mut() {
+            if !voice.gate && voice.envelope.is_idle() {
+                voice.note_index = None;
+            }
+        }
+    }
+
+    fn follow_notes(&mut self, synth_state: &SynthState) {
+        let states = &synth_state.note_index_state;
+
+        if let Some(note_index) = synth_state.mono.legato_note() {
+            self.glide_voice(note_index, states);
+        }
+
+        for voice in self.voices.iter_mut() {
+            let note_index = match voice.note_index {
+                Some(note_index) => note_index as usize,
+                None => continue,
+            };
+
+            let pressed = states[note_index] == NoteState::Pressed;
+            let held = Self::is_held(states[note_index]);
+
+            if (pressed && !voice.pressed) || (held && !voice.gate) {
+                voice.envelope.gate_on();
+            } else if !held && voice.gate {
+                voice.envelope.gate_off();
+            }
+
+            voice.gate = held;
+            voice.pressed = pressed;
+        }
+
+        for (note_index, state) in states.iter().enumerate() {
+            let has_voice = self.voices.iter().any(|voice| voice.note_index == Some(note_index as u8));
+
+            if Self::is_held(*state) && !has_voice {
+                self.start_voice(note_index as u8, *state == NoteState::Pressed);
+            }
+        }
+    }
+
+    /// Moves the voice of the note mono legato left onto its new note without retriggering.
+    fn glide_voice(&mut self, note_index: u8, states: &[NoteState; NUM_NOTES]) {
+        if self.voices.iter().any(|voice| voice.note_index == Some(note_index)) {
+            return;
+        }
+
+        // Still gated from before the previous note was released
+        let previous = self.voices.iter_mut().find(|voice| match voice.note_index {
+            Some(previous) => voice.gate && !Self::is_held(states[previous as usize]),
+            None => false,
+        });
+
+        if let Some(voice) = previous {
+            let millihertz = midi_note_millihertz(SynthState::note_index_to_midi(note_index));
+
+            voice.oscillator.set_frequency(millihertz, self.sample_rate);
+            voice.note_index = Some(note_index);
+        }
+    }
+
+    fn is_held(state: NoteState) -> bool {
+        matches!(state, NoteState::Pressed | NoteState::Sustain)
+    }
+
+    fn start_voice(&mut self, note_index: u8, pressed: bool) {
+        let free = self.voices.iter().position(|voice| voice.note_index.is_none());
+
+        // A free voice, otherwise the quietest one releasing
+        let voice = match free {
+            Some(voice) => voice,
+            None => match self
+                .voices
+                .iter()
+                .enumerate()
+                .filter(|(_, voice)| !voice.gate)
+                .min_by_key(|(_, voice)| voice.envelope.level())
+            {
+                Some((voice, _)) => voice,
+                None => return,
+            },
+        };
+
+        let millihertz = midi_note_millihertz(SynthState::note_index_to_midi(note_index));
+        let voice = &mut self.voices[voice];
+
+        voice.note_index = Some(note_index);
+        voice.gate = true;
+        voice.pressed = pressed;
+        voice.oscillator.set_frequency(millihertz, self.sample_rate);
+        // A stolen voice keeps its phase and attacks from its current level, so it does not click
+        if free.is_some() {
+            voice.oscillator.reset();
+        }
+        voice.envelope.gate_on();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::{fs, path::PathBuf, vec, vec::Vec};
+
+    use keyboard_matrix::KeyboardState;
+
+    use crate::{AudioEngine, NoteState, SynthEngine, SynthState, Waveform, AUDIO_VOICES};
+
+    const SAMPLE_RATE: u32 = 16_000;
+    const BLOCK: usize = 64;
+
+    // A4, MIDI note 69
+    const A4: usize = 45;
+
+    /// Reference renders land in target/renders for listening to.
+    fn write_wav(name: &str, samples: &[i16]) {
+        let directory = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("renders");
+        fs::create_dir_all(&directory).unwrap();
+
+        let data_len = samples.len() as u32 * 2;
+        let mut wav = Vec::new();
+
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // Mono
+        wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        for sample in samples {
+            wav.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        fs::write(directory.join(name), wav).unwrap();
+    }
+
+    /// Renders `samples` holding `note_indices`, Pressed for the first block then Sustain.
+    fn render_held(audio: &mut AudioEngine, note_indices: &[usize], samples: usize) -> Vec<i16> {
+        let mut synth_state = SynthState::new();
+        let mut output = vec![0; samples];
+
+        for (block, buffer) in output.chunks_mut(BLOCK).enumerate() {
+            let state = if block == 0 { NoteState::Pressed } else { NoteState::Sustain };
+            for note_index in note_indices {
+                synth_state.note_index_state[*note_index] = state;
+            }
+
+            audio.render(&synth_state, buffer);
+        }
+
+        output
+    }
+
+    fn rising_zero_crossings(samples: &[i16]) -> usize {
+        samples.windows(2).filter(|pair| pair[0] < 0 && pair[1] >= 0).count()
+    }
+
+    #[test]
+    fn renders_each_waveform_at_pitch() {
+        let waveforms = [
+            (Waveform::Saw, "a4_saw.wav"),
+            (Waveform::Square, "a4_square.wav"),
+            (Waveform::Triangle, "a4_triangle.wav"),
+            (Waveform::Sine, "a4_sine.wav"),
+        ];
+
+        for (waveform, name) in waveforms {
+            let mut audio = AudioEngine::new(SAMPLE_RATE);
+            audio.waveform = waveform;
+
+            let samples = render_held(&mut audio, &[A4], SAMPLE_RATE as usize);
+            write_wav(name, &samples);
+
+            let crossings = rising_zero_crossings(&samples);
+            assert!((438..=441).contains(&crossings), "{:?} {}", waveform, crossings);
+
+            let peak = samples.iter().map(|sample| (*sample as i32).abs()).max().unwrap();
+            assert!(peak > 8_000 && peak <= 16_384, "{:?} {}", waveform, peak);
+        }
+    }
+
+    #[test]
+    fn release_fades_out_after_note_off() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = KeyboardState::default();
+        let mut audio = AudioEngine::new(SAMPLE_RATE);
+        synth_engine.state.attack_ms = 5;
+        synth_engine.state.release_ms = 100;
+        audio.decay_ms = 0;
+        audio.sustain = 32_768;
+
+        let mut samples = vec![0; SAMPLE_RATE as usize / 2];
+        let mut blocks = samples.chunks_mut(BLOCK);
+
+        // Key 13 plays C4
+        keyboard_state.state.insert(13);
+        for _ in 0..50 {
+            synth_engine.update(4, &keyboard_state);
+            audio.render(&synth_engine.state, blocks.next().unwrap());
+        }
+
+        // The envelope releases over the 100ms the note spends in Release, 1600 samples or 25 blocks
+        keyboard_state.state.remove(13);
+        for _ in 0..25 {
+            synth_engine.update(4, &keyboard_state);
+            let fading = blocks.next().unwrap();
+            audio.render(&synth_engine.state, fading);
+            assert!(fading.iter().any(|sample| *sample != 0));
+        }
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Release);
+
+        for block in blocks {
+            synth_engine.update(4, &keyboard_state);
+            audio.render(&synth_engine.state, block);
+        }
+        write_wav("c4_release.wav", &samples);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Off);
+
+        let silent_from = 75 * BLOCK;
+        assert!(samples[silent_from..].iter().all(|sample| *sample == 0));
+        assert_eq!(audio.sounding_voices(), 0);
+    }
+
+    #[test]
+    fn chord_saturates_instead_of_wrapping() {
+        let mut audio = AudioEngine::new(SAMPLE_RATE);
+        audio.waveform = Waveform::Square;
+        audio.sustain = 32_768;
+        audio.volume = 32_768;
+
+        let samples = render_held(&mut audio, &[36, 40, 43, 48], SAMPLE_RATE as usize / 4);
+        write_wav("c_major_square.wav", &samples);
+
+        assert!(samples.contains(&i16::MAX));
+        assert!(samples.contains(&i16::MIN));
+    }
+
+    #[test]
+    fn extra_notes_take_the_quietest_voice() {
+        let mut audio = AudioEngine::new(SAMPLE_RATE);
+        let mut synth_state = SynthState::new();
+        let mut buffer = [0; BLOCK];
+        audio.decay_ms = 0;
+        audio.sustain = 32_768;
+        synth_state.release_ms = 300;
+
+        for note_index in 0..AUDIO_VOICES {
+            synth_state.note_index_state[36 + note_index] = NoteState::Sustain;
+        }
+        audio.render(&synth_state, &mut buffer);
+
+        synth_state.note_index_state[37] = NoteState::Release;
+        audio.render(&synth_state, &mut buffer);
+
+        synth_state.note_index_state[48] = NoteState::Pressed;
+        audio.render(&synth_state, &mut buffer);
+
+        assert_eq!(audio.sounding_voices(), AUDIO_VOICES);
+        assert!(audio.voices.iter().any(|voice| voice.note_index == Some(48)));
+        assert!(!audio.voices.iter().any(|voice| voice.note_index == Some(37)));
+    }
+
+    #[test]
+    fn held_notes_keep_their_voices() {
+        let mut audio = AudioEngine::new(SAMPLE_RATE);
+        let mut synth_state = SynthState::new();
+        let mut buffer = [0; BLOCK];
+
+        for note_index in 0..=AUDIO_VOICES {
+            synth_state.note_index_state[36 + note_index] = NoteState::Sustain;
+        }
+        audio.render(&synth_state, &mut buffer);
+        let first = audio.voices.map(|voice| voice.note_index);
+
+        for _ in 0..10 {
+            audio.render(&synth_state, &mut buffer);
+            assert_eq!(audio.voices.map(|voice| voice.note_index), first);
+        }
+
+        // The waiting note takes over the voice of a released note
+        synth_state.note_index_state[36] = NoteState::Off;
+        for _ in 0..100 {
+            audio.render(&synth_state, &mut buffer);
+        }
+        assert!(audio.voices.iter().any(|voice| voice.note_index == Some(36 + AUDIO_VOICES as u8)));
+        assert!(!audio.voices.iter().any(|voice| voice.note_index == Some(36)));
+    }
+}
@@ -0,0 +1,94 @@
+/// Most notes in a chord.
+pub const MAX_CHORD_NOTES: usize = 6;
+
+/// Notes a single key plays in chord mode, as semitones above the root.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChordShape {
+    semitones: [u8; MAX_CHORD_NOTES],
+    // Scale degrees above the root used instead of semitones when diatonic, None for custom shapes
+    degrees: Option<[u8; MAX_CHORD_NOTES]>,
+    len: u8,
+}
+
+impl ChordShape {
+    pub const MAJOR: ChordShape = ChordShape::triad([0, 4, 7, 0, 0, 0]);
+    pub const MINOR: ChordShape = ChordShape::triad([0, 3, 7, 0, 0, 0]);
+    /// Dominant seventh, or the scale's own seventh chord when diatonic.
+    pub const SEVENTH: ChordShape = ChordShape {
+        semitones: [0, 4, 7, 10, 0, 0],
+        degrees: Some([0, 2, 4, 6, 0, 0]),
+        len: 4,
+    };
+    /// Root, fifth and octave.  Only defined in semitones since the degrees of a fifth and an octave depend
+    /// on the scale length, so diatonic mode snaps it down to the scale like a custom shape.
+    pub const POWER: ChordShape = ChordShape {
+        semitones: [0, 7, 12, 0, 0, 0],
+        degrees: None,
+        len: 3,
+    };
+
+    const fn triad(semitones: [u8; MAX_CHORD_NOTES]) -> Self {
+        Self {
+            semitones,
+            degrees: Some([0, 2, 4, 0, 0, 0]),
+            len: 3,
+        }
+    }
+
+    /// A chord of 1 - `MAX_CHORD_NOTES` strictly ascending semitone offsets from a root of 0, spanning at
+    /// most two octaves.
+    pub fn custom(semitones: &[u8]) -> Option<ChordShape> {
+        if semitones.is_empty() || semitones.len() > MAX_CHORD_NOTES || semitones[0] != 0 {
+            return None;
+        }
+
+        if semitones.windows(2).any(|pair| pair[0] >= pair[1]) || semitones[semitones.len() - 1] > 24 {
+            return None;
+        }
+
+        let mut table = [0; MAX_CHORD_NOTES];
+        table[..semitones.len()].copy_from_slice(semitones);
+
+        Some(ChordShape {
+            semitones: table,
+            degrees: None,
+            len: semitones.len() as u8,
+        })
+    }
+
+    pub fn semitones(&self) -> &[u8] {
+        &self.semitones[..self.len as usize]
+    }
+
+    /// Scale degrees above the root for a diatonic chord, None if the shape is only defined in semitones.
+    pub fn degrees(&self) -> Option<&[u8]> {
+        self.degrees.as_ref().map(|degrees| &degrees[..self.len as usize])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ChordShape;
+
+    #[test]
+    fn built_in_shapes() {
+        assert_eq!(ChordShape::MAJOR.semitones(), [0, 4, 7]);
+        assert_eq!(ChordShape::MINOR.degrees(), Some(&[0, 2, 4][..]));
+        assert_eq!(ChordShape::SEVENTH.semitones(), [0, 4, 7, 10]);
+        assert_eq!(ChordShape::POWER.degrees(), None);
+    }
+
+    #[test]
+    fn custom_shape_validation() {
+        let sus4 = ChordShape::custom(&[0, 5, 7]).unwrap();
+
+        assert_eq!(sus4.semitones(), [0, 5, 7]);
+        assert_eq!(sus4.degrees(), None);
+
+        assert_eq!(ChordShape::custom(&[]), None);
+        assert_eq!(ChordShape::custom(&[1, 5]), None);
+        assert_eq!(ChordShape::custom(&[0, 7, 5]), None);
+        assert_eq!(ChordShape::custom(&[0, 25]), None);
+        assert_eq!(ChordShape::custom(&[0, 1, 2, 3, 4, 5, 6]), None);
+    }
+}
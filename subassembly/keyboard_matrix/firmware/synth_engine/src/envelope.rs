@@ -0,0 +1,211 @@
+use crate::Q15_ONE;
+
+// Levels carry 8 extra bits so multi second segments still move every sample
+const LEVEL_SHIFT: u32 = 8;
+const LEVEL_MAX: i32 = Q15_ONE << LEVEL_SHIFT;
+
+/// Attack, decay, sustain and release settings shared by every voice.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Adsr {
+    /// Time to rise from silence to full scale.
+    pub attack_ms: u16,
+    /// Time to fall from full scale to silence, the decay to `sustain` takes its share of it.
+    pub decay_ms: u16,
+    /// Q15 level held while the note is held, 0 - 32768.
+    pub sustain: u16,
+    /// Time to fall from full scale to silence once the note is released.
+    pub release_ms: u16,
+}
+
+impl Default for Adsr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Adsr {
+    /// 5ms attack, 200ms decay to 70% and a 300ms release.
+    pub const fn new() -> Self {
+        Self {
+            attack_ms: 5,
+            decay_ms: 200,
+            sustain: 22_938,
+            release_ms: 300,
+        }
+    }
+}
+
+/// Per sample steps for an `Adsr` at a sample rate, worked out once per block to keep divisions out of the
+/// sample loop.
+#[derive(Clone, Copy)]
+pub struct EnvelopeRates {
+    attack: i32,
+    decay: i32,
+    sustain: i32,
+    release: i32,
+}
+
+impl EnvelopeRates {
+    pub fn new(adsr: &Adsr, sample_rate: u32) -> Self {
+        Self {
+            attack: Self::step(adsr.attack_ms, sample_rate),
+            decay: Self::step(adsr.decay_ms, sample_rate),
+            sustain: (adsr.sustain as i32).min(Q15_ONE) << LEVEL_SHIFT,
+            release: Self::step(adsr.release_ms, sample_rate),
+        }
+    }
+
+    fn step(time_ms: u16, sample_rate: u32) -> i32 {
+        let samples = (time_ms as u64 * sample_rate as u64 / 1000).clamp(1, LEVEL_MAX as u64) as i32;
+
+        // Rounded up so the segment ends within its time
+        (LEVEL_MAX + samples - 1) / samples
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum EnvelopeStage {
+    #[default]
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Linear ADSR envelope for one voice, producing a Q15 gain per sample.
+#[derive(Clone, Copy, Default)]
+pub struct Envelope {
+    stage: EnvelopeStage,
+    level: i32,
+}
+
+impl Envelope {
+    pub fn stage(&self) -> EnvelopeStage {
+        self.stage
+    }
+
+    /// Current Q15 gain.
+    pub fn level(&self) -> i32 {
+        self.level >> LEVEL_SHIFT
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.stage == EnvelopeStage::Idle
+    }
+
+    /// Starts the attack from the current level, so retriggering a sounding voice does not click.
+    pub fn gate_on(&mut self) {
+        self.stage = EnvelopeStage::Attack;
+    }
+
+    pub fn gate_off(&mut self) {
+        if self.stage != EnvelopeStage::Idle {
+            self.stage = EnvelopeStage::Release;
+        }
+    }
+
+    /// Silences the envelope straight away.
+    pub fn reset(&mut self) {
+        self.stage = EnvelopeStage::Idle;
+        self.level = 0;
+    }
+
+    pub fn next(&mut self, rates: &EnvelopeRates) -> i32 {
+        match self.stage {
+            EnvelopeStage::Idle => {}
+            EnvelopeStage::Attack => {
+                self.level += rates.attack;
+                if self.level >= LEVEL_MAX {
+                    self.level = LEVEL_MAX;
+                    self.stage = EnvelopeStage::Decay;
+                }
+            }
+            EnvelopeStage::Decay => {
+                self.level -= rates.decay;
+                if self.level <= rates.sustain {
+                    self.level = rates.sustain;
+                    self.stage = EnvelopeStage::Sustain;
+                }
+            }
+            EnvelopeStage::Sustain => {
+                // Follows sustain level changes while held
+                self.level = rates.sustain;
+            }
+            EnvelopeStage::Release => {
+                self.level -= rates.release;
+                if self.level <= 0 {
+                    self.level = 0;
+                    self.stage = EnvelopeStage::Idle;
+                }
+            }
+        }
+
+        self.level()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Adsr, Envelope, EnvelopeRates, EnvelopeStage, Q15_ONE};
+
+    #[test]
+    fn stages_follow_settings() {
+        let adsr = Adsr { attack_ms: 10, decay_ms: 20, sustain: 16_384, release_ms: 40 };
+        let rates = EnvelopeRates::new(&adsr, 1000);
+        let mut envelope = Envelope::default();
+
+        assert_eq!(envelope.next(&rates), 0);
+
+        envelope.gate_on();
+        for _ in 0..10 {
+            envelope.next(&rates);
+        }
+        assert_eq!(envelope.level(), Q15_ONE);
+
+        // Half way down to silence is 10 of the 20ms
+        for _ in 0..10 {
+            envelope.next(&rates);
+        }
+        assert_eq!(envelope.stage(), EnvelopeStage::Sustain);
+        assert_eq!(envelope.next(&rates), 16_384);
+
+        envelope.gate_off();
+        for _ in 0..19 {
+            envelope.next(&rates);
+        }
+        assert!(envelope.level() > 0);
+        envelope.next(&rates);
+        assert!(envelope.is_idle());
+    }
+
+    #[test]
+    fn retrigger_attacks_from_current_level() {
+        let adsr = Adsr { attack_ms: 100, decay_ms: 0, sustain: 32_768, release_ms: 100 };
+        let rates = EnvelopeRates::new(&adsr, 1000);
+        let mut envelope = Envelope::default();
+
+        envelope.gate_on();
+        for _ in 0..50 {
+            envelope.next(&rates);
+        }
+        envelope.gate_off();
+        let released = envelope.next(&rates);
+
+        envelope.gate_on();
+        assert!(envelope.next(&rates) > released);
+    }
+
+    #[test]
+    fn zero_times_are_immediate() {
+        let adsr = Adsr { attack_ms: 0, decay_ms: 0, sustain: 32_768, release_ms: 0 };
+        let rates = EnvelopeRates::new(&adsr, 48_000);
+        let mut envelope = Envelope::default();
+
+        envelope.gate_on();
+        assert_eq!(envelope.next(&rates), Q15_ONE);
+
+        envelope.gate_off();
+        assert_eq!(envelope.next(&rates), 0);
+    }
+}
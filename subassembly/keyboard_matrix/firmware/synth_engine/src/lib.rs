@@ -2,9 +2,42 @@
 
 use core::u8;
 
-use keyboard_matrix::KeyboardState;
+use keyboard_matrix::{KeyAction, KeySet, KeyboardState, Keymap, KIB_KEYMAP};
+
+mod arpeggiator;
+mod audio;
+mod chord_mode;
+mod envelope;
+mod looper;
+mod midi;
+mod midi_in;
+mod mono;
+mod note_set;
+mod oscillator;
+mod scale;
+mod voice;
+
+pub use arpeggiator::*;
+pub use audio::*;
+pub use chord_mode::*;
+pub use envelope::*;
+pub use looper::*;
+pub use midi::*;
+pub use midi_in::*;
+pub use mono::*;
+pub use note_set::{NoteOrder, NoteSet, NOTE_ORDER_SIZE};
+pub use oscillator::*;
+pub use scale::Scale;
+pub use voice::*;
 
 const MIDI_NOTE_OFFSET : u8 = 24; //0th note is C1
+
+/// `KeyAction::Function` that holds the sustain pedal down while its key is held.
+pub const FUNCTION_SUSTAIN: u8 = 0;
+/// `KeyAction::Function` that records a loop, then switches it between playing and overdubbing.
+pub const FUNCTION_LOOP: u8 = 1;
+/// `KeyAction::Function` that stops the loop, or clears it when already stopped.
+pub const FUNCTION_LOOP_STOP: u8 = 2;
 pub const NUM_NOTES : usize = 97; //8 octaves, 12 notes per octave, plus 1 extra C in octave 8
 
 /// State of a note
@@ -58,6 +91,23 @@ pub struct SynthState {
     pub octave: u8, // 1 - 8
     pub note_index_state: [NoteState; NUM_NOTES], // Tuning from C1 to C9 (extra C in octave 8).  Requires MIDI_NOTE_OFFSET to be accurate midi note value.
     pub dirty: bool,
+    pub keymap: Keymap,
+    pub external_notes: NoteSet, // Notes held by MIDI input, kept apart so they are not echoed to MIDI output
+    pub transpose: i8, // Semitones, -12 - 12
+    pub scale: Scale, // Note keys play successive degrees of the scale
+    pub latch: bool, // Pressing a note key toggles its note instead of playing it while held
+    pub sustain_pedal: bool, // Pedal held by I2C or MIDI CC64, a FUNCTION_SUSTAIN key also holds it
+    pub latched_notes: NoteSet,
+    pub sustained_notes: NoteSet, // Notes kept sounding by the pedal after their keys were released
+    pub arpeggiator: Arpeggiator, // When enabled plays the held notes in sequence instead of together
+    pub chord: Option<ChordShape>, // Each note key plays this chord rooted on its note
+    pub chord_diatonic: bool, // Chord notes follow a non-chromatic scale rather than exact semitones
+    pub mono: MonoMode, // When enabled only one held note sounds
+    pub voices: VoiceAllocator, // When enabled only notes with a voice sound
+    pub attack_ms: u16, // Least time a note stays Pressed before Sustain, 0 for a single update
+    pub release_ms: u16, // Time a note stays in Release before Off, 0 for a single update
+    pub looper: Looper, // Loops recorded notes back along with the live ones
+    note_state_ms: [u16; NUM_NOTES], // Time each note has spent in its current state
 }
 
 
@@ -67,45 +117,121 @@ impl SynthState {
             octave: 4,
             note_index_state: [NoteState::Off; NUM_NOTES],
             dirty: false,
+            keymap: KIB_KEYMAP,
+            external_notes: NoteSet::EMPTY,
+            transpose: 0,
+            scale: Scale::CHROMATIC,
+            latch: false,
+            sustain_pedal: false,
+            latched_notes: NoteSet::EMPTY,
+            sustained_notes: NoteSet::EMPTY,
+            arpeggiator: Arpeggiator::new(),
+            chord: None,
+            chord_diatonic: false,
+            mono: MonoMode::new(),
+            voices: VoiceAllocator::new(),
+            attack_ms: 0,
+            release_ms: 0,
+            looper: Looper::new(),
+            note_state_ms: [0; NUM_NOTES],
         }
     }
-    
-    fn index_to_note_offset(&self, idx: u8) -> u8 {
-        match idx {
-            11=>3, //D#
-            12=>1, //C#
-            13=>0, //C
-            14=>2, //D
-            10=>6, //F#
-            15=>4, //E
-            16=>5, //F
-            17=>7, //G
-            8=>10, //A#
-            9=>8, //G#
-            18=>9, //A
-            19=>11, //B
-            20=>12, //C2
-            _=>0,
+
+    /// Milliseconds the note has been in its current state, saturating at `u16::MAX`.
+    pub fn note_state_ms(&self, note_index: u8) -> u16 {
+        self.note_state_ms[note_index as usize]
+    }
+
+    /// Notes a note key plays, its own note or the chord rooted on it in chord mode.
+    pub fn key_notes(&self, note_offset: u8) -> NoteSet {
+        let mut notes = NoteSet::EMPTY;
+
+        let root = match self.note_offset_to_note_index(note_offset) {
+            Some(root) => root,
+            None => return notes,
+        };
+
+        let chord = match self.chord {
+            Some(chord) => chord,
+            None => {
+                notes.insert(root as usize);
+                return notes;
+            }
+        };
+
+        let diatonic = self.chord_diatonic && self.scale != Scale::CHROMATIC;
+
+        match chord.degrees() {
+            Some(degrees) if diatonic => {
+                for degree in degrees {
+                    if let Some(note_index) = self.note_offset_to_note_index(note_offset + degree) {
+                        notes.insert(note_index as usize);
+                    }
+                }
+            }
+            _ => {
+                for semitones in chord.semitones() {
+                    let target = root + semitones;
+
+                    if !diatonic {
+                        notes.insert(target as usize);
+                        continue;
+                    }
+
+                    // Custom shapes snap each note down to the scale
+                    let mut degree = note_offset;
+                    let mut note_index = root;
+                    while let Some(next) = self.note_offset_to_note_index(degree + 1) {
+                        if next > target {
+                            break;
+                        }
+
+                        degree += 1;
+                        note_index = next;
+                    }
+
+                    notes.insert(note_index as usize);
+                }
+            }
         }
+
+        notes
+    }
+
+    /// Keys playing any of `notes` in the selected octave.
+    pub fn keys_for_notes(&self, notes: &NoteSet) -> KeySet {
+        (0..13)
+            .filter(|note_offset| {
+                let note_index = self.note_offset_to_note_index(*note_offset);
+                note_index.is_some_and(|note_index| notes.contains(note_index as usize))
+            })
+            .filter_map(|note_offset| self.keymap.key_for(KeyAction::Note(note_offset)))
+            .collect()
     }
 
+    /// Keys playing a note held by MIDI input in the selected octave.
+    pub fn external_keys(&self) -> KeySet {
+        self.keys_for_notes(&self.external_notes)
+    }
+
+    /// Keys playing the note the arpeggiator is sounding.
+    pub fn arpeggiated_keys(&self) -> KeySet {
+        self.keys_for_notes(&self.arpeggiator.notes())
+    }
+
+    /// Keys playing a note the looper is sounding.
+    pub fn looped_keys(&self) -> KeySet {
+        self.keys_for_notes(&self.looper.notes())
+    }
+
+    pub fn midi_to_note_index(midi_note: u8) -> Option<u8> {
+        midi_note
+            .checked_sub(MIDI_NOTE_OFFSET)
+            .filter(|note_index| (*note_index as usize) < NUM_NOTES)
+    }
+    
     pub fn note_offset_to_index(&self, note_offset: u8) -> u8 {
-        match note_offset {
-            3=>11, //D#
-            1=>12, //C#
-            0=>13, //C
-            2=>14, //D
-            6=>10, //F#
-            4=>15, //E
-            5=>16, //F
-            7=>17, //G
-            10=>8, //A#
-            8=>9, //G#
-            9=>18, //A
-            11=>19, //B
-            12=>20, //C2
-            _=>0,
-        }
+        self.keymap.key_for(KeyAction::Note(note_offset)).unwrap_or(0) as u8
     }
 
     fn octave_note_offset_to_note_index(octave: u8, note_offset: u8) -> u8 {
@@ -114,8 +240,16 @@ impl SynthState {
         octave_offset + note_offset
     }
 
-    pub fn note_offset_to_note_index(&self, note_offset: u8) -> u8 {
-        SynthState::octave_note_offset_to_note_index(self.octave, note_offset)
+    /// Note played by a note key after scale and transpose, if it falls within the synth range.
+    pub fn note_offset_to_note_index(&self, note_offset: u8) -> Option<u8> {
+        let root = SynthState::octave_note_offset_to_note_index(self.octave, 0) as i16;
+        let note_index = root + self.transpose as i16 + self.scale.semitones(note_offset) as i16;
+
+        if (0..NUM_NOTES as i16).contains(&note_index) {
+            Some(note_index as u8)
+        } else {
+            None
+        }
     }
 
     pub fn note_index_to_note_offset(&self, note_index: u8) -> u8 {
@@ -124,13 +258,6 @@ impl SynthState {
         note_index - octave_offset
     }
 
-    fn index_to_note_index(&self, idx: u8) -> u8 {
-        let note_offset = self.index_to_note_offset(idx);
-        let note_index = self.note_offset_to_note_index(note_offset);
-
-        note_index
-    }
-
     #[inline(never)]
     pub fn note_index_to_midi(note_index: u8) -> u8 {
         let midi_note = MIDI_NOTE_OFFSET + note_index;
@@ -141,9 +268,27 @@ impl SynthState {
     #[inline(never)]
     fn activate_note_index(&mut self, note_index: u8) -> bool {
         let note_index = note_index as usize;
-        let new_state = self.note_index_state[note_index].activate();
+        let new_state = match self.note_index_state[note_index] {
+            NoteState::Pressed if self.note_state_ms[note_index] < self.attack_ms => NoteState::Pressed,
+            state => state.activate(),
+        };
         if self.note_index_state[note_index] != new_state {
             self.note_index_state[note_index] = new_state;
+            self.note_state_ms[note_index] = 0;
+            self.dirty = true;
+
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Moves straight to Sustain so the note sounds without a new attack.
+    fn legato_note_index(&mut self, note_index: u8) -> bool {
+        let note_index = note_index as usize;
+        if !matches!(self.note_index_state[note_index], NoteState::Pressed | NoteState::Sustain) {
+            self.note_index_state[note_index] = NoteState::Sustain;
+            self.note_state_ms[note_index] = 0;
             self.dirty = true;
 
             true
@@ -155,9 +300,13 @@ impl SynthState {
     #[inline(never)]
     fn deactivate_note_index(&mut self, note_index: u8) -> bool {
         let note_index = note_index as usize;
-        let new_state = self.note_index_state[note_index].deactivate();
+        let new_state = match self.note_index_state[note_index] {
+            NoteState::Release if self.note_state_ms[note_index] < self.release_ms => NoteState::Release,
+            state => state.deactivate(),
+        };
         if self.note_index_state[note_index] != new_state {
             self.note_index_state[note_index] = new_state;
+            self.note_state_ms[note_index] = 0;
             self.dirty = true;
 
             true
@@ -169,64 +318,206 @@ impl SynthState {
 
 pub struct SynthEngine {
     pub state: SynthState,
+    pub midi: MidiOutput,
+    pub midi_parser: MidiParser,
 }
 
 impl SynthEngine {
     pub fn new() -> Self {
         Self {
-            state: SynthState::new()
+            state: SynthState::new(),
+            midi: MidiOutput::new(),
+            midi_parser: MidiParser::new(),
+        }
+    }
+
+    /// Parses a chunk of a MIDI byte stream, applying each complete message.  Messages may span chunks.
+    pub fn receive_midi_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            if let Some(message) = self.midi_parser.parse(*byte) {
+                self.receive_midi(&message);
+            }
+        }
+    }
+
+    /// Applies a MIDI message from any channel.  Notes outside the synth range are ignored.
+    pub fn receive_midi(&mut self, message: &MidiMessage) {
+        match *message {
+            MidiMessage::NoteOn { note, .. } | MidiMessage::NoteOff { note, .. } => {
+                if let Some(note_index) = SynthState::midi_to_note_index(note) {
+                    let held = matches!(message, MidiMessage::NoteOn { .. });
+
+                    if self.state.external_notes.contains(note_index as usize) != held {
+                        self.state.external_notes.set(note_index as usize, held);
+                        self.state.dirty = true;
+                    }
+                }
+            }
+            MidiMessage::ControlChange { controller: CC_SUSTAIN, value, .. } => {
+                self.set_sustain_pedal(value >= 64);
+            }
+            MidiMessage::ControlChange { controller: CC_ALL_SOUND_OFF | CC_ALL_NOTES_OFF, .. }
+                if !self.state.external_notes.is_empty() =>
+            {
+                self.state.external_notes.clear();
+                self.state.dirty = true;
+            }
+            _ => {}
         }
     }
 
+    /// Serialises note changes since the last call as MIDI into `buffer`, returning the bytes written.
+    pub fn write_midi(&mut self, buffer: &mut [u8]) -> usize {
+        self.midi.write(&self.state, buffer)
+    }
+
     pub fn set_octave(&mut self, octave: u8) {
         self.state.octave = octave;
         self.state.dirty = true;
     }
 
+    /// Clamped to -12 - 12 semitones.  Held notes move on the next update.
+    pub fn set_transpose(&mut self, transpose: i8) {
+        self.state.transpose = transpose.clamp(-12, 12);
+        self.state.dirty = true;
+    }
+
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.state.scale = scale;
+        self.state.dirty = true;
+    }
+
+    /// None leaves chord mode.  Held chords change on the next update.
+    pub fn set_chord(&mut self, chord: Option<ChordShape>) {
+        self.state.chord = chord;
+        self.state.dirty = true;
+    }
+
+    /// Diatonic chords stack the scale's own degrees, so `ChordShape::MAJOR` and `ChordShape::MINOR` both
+    /// play the scale's triad on each key and only differ outside diatonic mode.
+    pub fn set_chord_diatonic(&mut self, diatonic: bool) {
+        self.state.chord_diatonic = diatonic;
+        self.state.dirty = true;
+    }
+
+    /// Turning latch off releases every latched note on the next update.
+    pub fn set_latch(&mut self, latch: bool) {
+        self.state.latch = latch;
+        self.state.latched_notes.clear();
+        self.state.dirty = true;
+    }
+
+    /// Like MIDI CC64, notes played while the pedal is down keep sounding until it is released.
+    pub fn set_sustain_pedal(&mut self, down: bool) {
+        self.state.sustain_pedal = down;
+        self.state.dirty = true;
+    }
+
     pub fn get_octave_notes(&self) -> (u8, [u8 ; 13]) {
         let mut notes: [u8; 13] = [0; 13];
 
         for ocatave_note in 0..13 {
-            let note_index = SynthState::octave_note_offset_to_note_index(self.state.octave, ocatave_note);
+            let note_index = self.state.note_offset_to_note_index(ocatave_note);
 
-            notes[ocatave_note as usize] = if self.state.note_index_state[note_index as usize].is_active() {
-                SynthState::note_index_to_midi(note_index)
-            } else {
-                0
+            notes[ocatave_note as usize] = match note_index {
+                Some(note_index) if self.state.note_index_state[note_index as usize].is_active() => {
+                    SynthState::note_index_to_midi(note_index)
+                }
+                _ => 0,
             }
         }
 
         (self.state.octave, notes)
     }
 
-    pub fn update(&mut self, keyboard_state: &KeyboardState) {
+    /// Advances by `delta_t_ms`, which times the arpeggiator and the attack and release of each note.
+    pub fn update(&mut self, delta_t_ms: u32, keyboard_state: &KeyboardState) {
         self.state.dirty = false;
 
-        // Update Octave
-        for i in 0..8 {
-        if keyboard_state.pressed[i] && self.state.octave != i as u8 + 1 {
-                self.state.octave = i as u8 + 1;
+        // Update Octave and loop controls
+        for key in keyboard_state.pressed {
+            match self.state.keymap.action(key) {
+                KeyAction::Octave(octave) if self.state.octave != octave => {
+                    self.state.octave = octave;
 
-                self.state.dirty = true;
+                    self.state.dirty = true;
+                }
+                KeyAction::Function(FUNCTION_LOOP) => self.state.looper.cycle(),
+                KeyAction::Function(FUNCTION_LOOP_STOP) => self.state.looper.stop_or_clear(),
+                _ => {}
             }
         }
 
-        //Clear notes for non-current octaves
-        for octave in 1..9 {
-            if octave != self.state.octave {
-                for i in 0..12 {
-                    let note_index = SynthState::octave_note_offset_to_note_index(octave, i);
+        // Update Notes, releasing any note no held key maps to such as those of other octaves
+        let mut held_notes = NoteSet::EMPTY;
+        let mut pedal_down = self.state.sustain_pedal;
+
+        for key in keyboard_state.state {
+            match self.state.keymap.action(key) {
+                KeyAction::Note(note_offset) => {
+                    held_notes = held_notes.union(self.state.key_notes(note_offset));
+                }
+                KeyAction::Function(FUNCTION_SUSTAIN) => pedal_down = true,
+                _ => {}
+            }
+        }
 
-                    self.state.dirty = self.state.deactivate_note_index(note_index) || self.state.dirty;
+        if self.state.latch {
+            for key in keyboard_state.pressed {
+                if let KeyAction::Note(note_offset) = self.state.keymap.action(key) {
+                    // A chord toggles as a whole, following its root
+                    let key_notes = self.state.key_notes(note_offset);
+                    let latched = self
+                        .state
+                        .note_offset_to_note_index(note_offset)
+                        .is_some_and(|root| self.state.latched_notes.contains(root as usize));
+
+                    self.state.latched_notes = if latched {
+                        self.state.latched_notes.difference(key_notes)
+                    } else {
+                        self.state.latched_notes.union(key_notes)
+                    };
                 }
             }
+
+            held_notes = self.state.latched_notes;
+        }
+
+        if pedal_down {
+            self.state.sustained_notes = self.state.sustained_notes.union(held_notes);
+        } else {
+            self.state.sustained_notes.clear();
         }
 
-        // Update Notes
-        for i in 8..21 {
-            let note_index = self.state.index_to_note_index(i);
+        let mut sounding_notes = held_notes.union(self.state.sustained_notes);
 
-            if keyboard_state.state[i as usize] {
+        let looped_notes = self.state.looper.update(delta_t_ms, &sounding_notes);
+        sounding_notes = sounding_notes.union(looped_notes);
+
+        if self.state.arpeggiator.enabled {
+            sounding_notes = self.state.arpeggiator.update(delta_t_ms, &sounding_notes);
+        }
+
+        let mut legato_note = None;
+
+        if self.state.mono.enabled {
+            sounding_notes = self.state.mono.update(&sounding_notes);
+            legato_note = self.state.mono.legato_note();
+        }
+
+        if self.state.voices.enabled {
+            sounding_notes = self.state.voices.update(&sounding_notes, &self.state.note_index_state);
+        }
+
+        let delta_t_ms = delta_t_ms.min(u16::MAX as u32) as u16;
+        for state_ms in self.state.note_state_ms.iter_mut() {
+            *state_ms = state_ms.saturating_add(delta_t_ms);
+        }
+
+        for note_index in 0..NUM_NOTES as u8 {
+            if legato_note == Some(note_index) && sounding_notes.contains(note_index as usize) {
+                self.state.dirty = self.state.legato_note_index(note_index) || self.state.dirty;
+            } else if sounding_notes.contains(note_index as usize) {
                 self.state.dirty = self.state.activate_note_index(note_index) || self.state.dirty;
             } else {
                 self.state.dirty = self.state.deactivate_note_index(note_index) || self.state.dirty;
@@ -237,7 +528,11 @@ impl SynthEngine {
 
 #[cfg(test)]
 mod test {
-    use crate::{SynthState, SynthEngine, MIDI_NOTE_OFFSET};
+    use crate::{
+        ArpOrder, ChordShape, LooperState, NotePriority, NoteState, Scale, StealPolicy, SynthState,
+        SynthEngine, VoiceEvent, FUNCTION_LOOP, FUNCTION_LOOP_STOP, FUNCTION_SUSTAIN, MIDI_NOTE_OFFSET,
+    };
+    use keyboard_matrix::KeyAction;
 
     #[test]
     fn octave_and_note_offset_for_C4_produce_expected_note_index() {
@@ -287,7 +582,7 @@ mod test {
         let mut synth_engine = SynthEngine::new();
         let keyboard_state = keyboard_matrix::KeyboardState::default();
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         assert_eq!(synth_engine.state.octave, 4);
     }
@@ -297,7 +592,7 @@ mod test {
         let mut synth_engine = SynthEngine::new();
         let mut keyboard_state = keyboard_matrix::KeyboardState::default();
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Off.to_int());
     }
@@ -307,9 +602,9 @@ mod test {
         let mut synth_engine = SynthEngine::new();
         let mut keyboard_state = keyboard_matrix::KeyboardState::default();
 
-        keyboard_state.state[13] = true;
+        keyboard_state.state.insert(13);
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Pressed.to_int());
     }
@@ -321,9 +616,9 @@ mod test {
 
         synth_engine.state.note_index_state[36] = crate::NoteState::Pressed;
 
-        keyboard_state.state[13] = true;
+        keyboard_state.state.insert(13);
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Sustain.to_int());
     }
@@ -335,9 +630,9 @@ mod test {
 
         synth_engine.state.note_index_state[36] = crate::NoteState::Sustain;
 
-        keyboard_state.state[13] = true;
+        keyboard_state.state.insert(13);
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Sustain.to_int());
     }
@@ -349,9 +644,9 @@ mod test {
 
         synth_engine.state.note_index_state[36] = crate::NoteState::Sustain;
 
-        keyboard_state.state[13] = false;
+        keyboard_state.state.remove(13);
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Release.to_int());
     }
@@ -363,9 +658,9 @@ mod test {
 
         synth_engine.state.note_index_state[36] = crate::NoteState::Release;
 
-        keyboard_state.state[13] = false;
+        keyboard_state.state.remove(13);
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Off.to_int());
     }
@@ -437,27 +732,50 @@ mod test {
     }
 
     #[test]
-    fn index_to_note_index_returns_0_for_C1() {
+    fn key_13_plays_C_of_selected_octave() {
         let mut synth_engine = SynthEngine::new();
         let mut keyboard_state = keyboard_matrix::KeyboardState::default();
 
         synth_engine.set_octave(1);
+        keyboard_state.state.insert(13);
 
-        let note_index = synth_engine.state.index_to_note_index(13);
+        synth_engine.update(0, &keyboard_state);
 
-        assert_eq!(note_index, 0);
+        assert_eq!(synth_engine.state.note_index_state[0].to_int(), crate::NoteState::Pressed.to_int());
     }
 
     #[test]
-    fn index_to_note_index_returns_36_for_C4() {
+    fn octave_key_plays_no_note() {
         let mut synth_engine = SynthEngine::new();
         let mut keyboard_state = keyboard_matrix::KeyboardState::default();
 
-        synth_engine.set_octave(4);
+        keyboard_state.state.insert(3);
 
-        let note_index = synth_engine.state.index_to_note_index(0);
+        synth_engine.update(0, &keyboard_state);
 
-        assert_eq!(note_index, 36);
+        assert!(synth_engine.state.note_index_state.iter().all(|note| !note.is_active()));
+    }
+
+    #[test]
+    fn keymap_layer_remaps_notes() {
+        static LAYERS: [[KeyAction; 21]; 2] = {
+            let mut upper = [KeyAction::Transparent; 21];
+            upper[13] = KeyAction::Note(4);
+            [keyboard_matrix::KIB_BASE_LAYER, upper]
+        };
+
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.state.keymap = keyboard_matrix::Keymap::new(&LAYERS);
+        synth_engine.state.keymap.set_layer(1, true);
+        keyboard_state.state.insert(13);
+
+        synth_engine.update(0, &keyboard_state);
+
+        assert!(!synth_engine.state.note_index_state[36].is_active());
+        assert_eq!(synth_engine.state.note_index_state[40].to_int(), crate::NoteState::Pressed.to_int());
+        assert_eq!(synth_engine.state.note_offset_to_index(4), 13);
     }
 
     #[test]
@@ -465,7 +783,7 @@ mod test {
         let mut synth_engine = SynthEngine::new();
         let mut keyboard_state = keyboard_matrix::KeyboardState::default();
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         let (octave, octave_notes) = synth_engine.get_octave_notes();
 
@@ -479,7 +797,7 @@ mod test {
 
         synth_engine.set_octave(2);
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         let (octave, octave_notes) = synth_engine.get_octave_notes();
 
@@ -491,7 +809,7 @@ mod test {
         let mut synth_engine = SynthEngine::new();
         let mut keyboard_state = keyboard_matrix::KeyboardState::default();
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         let (octave, octave_notes) = synth_engine.get_octave_notes();
 
@@ -500,14 +818,560 @@ mod test {
         }
     }
 
+    #[test]
+    fn octave_change_sends_note_off_then_note_on() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut buffer = [0; 8];
+
+        keyboard_state.state.insert(13);
+        synth_engine.update(0, &keyboard_state);
+        synth_engine.write_midi(&mut buffer);
+
+        keyboard_state.state.insert(4);
+        keyboard_state.pressed.insert(4);
+        synth_engine.update(0, &keyboard_state);
+
+        assert_eq!(synth_engine.write_midi(&mut buffer), 6);
+        assert_eq!(buffer[..6], [0x80, 60, 0x40, 0x90, 72, 100]);
+    }
+
+    #[test]
+    fn midi_note_on_marks_external_key() {
+        let mut synth_engine = SynthEngine::new();
+
+        // C4 and D4 on channel 3 using running status, then C5 which is key 20
+        synth_engine.receive_midi_bytes(&[0x92, 60, 100, 62]);
+        synth_engine.receive_midi_bytes(&[90, 72, 1]);
+
+        assert!(synth_engine.state.external_notes.contains(36));
+        assert_eq!(synth_engine.state.external_keys(), [13, 14, 20].into_iter().collect());
+        assert!(synth_engine.state.note_index_state.iter().all(|note| !note.is_active()));
+
+        synth_engine.receive_midi_bytes(&[0x82, 62, 0]);
+        assert_eq!(synth_engine.state.external_keys(), [13, 20].into_iter().collect());
+    }
+
+    #[test]
+    fn midi_all_notes_off_clears_external_notes() {
+        let mut synth_engine = SynthEngine::new();
+
+        synth_engine.receive_midi_bytes(&[0x90, 60, 100, 0xB0, 123, 0]);
+
+        assert!(synth_engine.state.external_notes.is_empty());
+        assert!(synth_engine.state.dirty);
+    }
+
+    #[test]
+    fn midi_notes_outside_range_are_ignored() {
+        let mut synth_engine = SynthEngine::new();
+
+        synth_engine.receive_midi_bytes(&[0x90, 23, 100, 121, 100]);
+
+        assert!(synth_engine.state.external_notes.is_empty());
+        assert_eq!(SynthState::midi_to_note_index(120), Some(96));
+    }
+
+    #[test]
+    fn transpose_shifts_played_and_reported_notes() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_transpose(2);
+        keyboard_state.state.insert(13);
+
+        synth_engine.update(0, &keyboard_state);
+
+        assert!(!synth_engine.state.note_index_state[36].is_active());
+        assert!(synth_engine.state.note_index_state[38].is_active());
+        assert_eq!(synth_engine.get_octave_notes().1[0], 62);
+    }
+
+    #[test]
+    fn major_scale_keys_play_successive_degrees() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_scale(Scale::MAJOR);
+        synth_engine.set_transpose(-3); // A minor root on the C key
+
+        // Note offsets 2 and 12 are keys 14 and 20, the third and the sixth an octave up
+        keyboard_state.state.insert(14);
+        keyboard_state.state.insert(20);
+
+        synth_engine.update(0, &keyboard_state);
+
+        let (_, octave_notes) = synth_engine.get_octave_notes();
+
+        assert_eq!(octave_notes[2], 60 - 3 + 4);
+        assert_eq!(octave_notes[12], 60 - 3 + 21);
+        assert_eq!(octave_notes[7], 0);
+        assert!(synth_engine.state.note_index_state[36 - 3 + 4].is_active());
+    }
+
+    #[test]
+    fn notes_beyond_range_are_not_played() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_octave(8);
+        synth_engine.set_scale(Scale::PENTATONIC);
+        keyboard_state.state.insert(20);
+
+        synth_engine.update(0, &keyboard_state);
+
+        assert_eq!(synth_engine.state.note_offset_to_note_index(12), None);
+        assert!(synth_engine.state.note_index_state.iter().all(|note| !note.is_active()));
+    }
+
+    #[test]
+    fn changing_scale_releases_previous_note() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        keyboard_state.state.insert(20);
+        synth_engine.update(0, &keyboard_state);
+
+        synth_engine.set_scale(Scale::MAJOR);
+        synth_engine.update(0, &keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[48].to_int(), crate::NoteState::Release.to_int());
+        assert_eq!(synth_engine.state.note_index_state[57].to_int(), crate::NoteState::Pressed.to_int());
+    }
+
+    fn press(keyboard_state: &mut keyboard_matrix::KeyboardState, key: usize) {
+        keyboard_state.state.insert(key);
+        keyboard_state.pressed.insert(key);
+    }
+
+    fn release(keyboard_state: &mut keyboard_matrix::KeyboardState, key: usize) {
+        keyboard_state.state.remove(key);
+        keyboard_state.pressed.remove(key);
+    }
+
+    #[test]
+    fn latch_press_toggles_note() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_latch(true);
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Pressed);
+
+        release(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        synth_engine.update(0, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Sustain);
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Release);
+
+        release(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Off);
+    }
+
+    #[test]
+    fn latch_off_releases_latched_notes() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_latch(true);
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        release(&mut keyboard_state, 13);
+
+        synth_engine.set_latch(false);
+        synth_engine.update(0, &keyboard_state);
+
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Release);
+    }
+
+    #[test]
+    fn sustain_pedal_holds_released_notes_until_lifted() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_sustain_pedal(true);
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        release(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        synth_engine.update(0, &keyboard_state);
+
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Sustain);
+
+        synth_engine.set_sustain_pedal(false);
+        synth_engine.update(0, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Release);
+
+        synth_engine.update(0, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Off);
+    }
+
+    #[test]
+    fn notes_released_before_pedal_down_are_not_sustained() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        release(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+
+        synth_engine.set_sustain_pedal(true);
+        synth_engine.update(0, &keyboard_state);
+
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Off);
+    }
+
+    #[test]
+    fn kib_function_layer_plays_synth_functions() {
+        let mut keymap = keyboard_matrix::KIB_KEYMAP;
+
+        keymap.set_layer(1, true);
+
+        assert_eq!(keymap.action(0), KeyAction::Function(FUNCTION_SUSTAIN));
+        assert_eq!(keymap.action(1), KeyAction::Function(FUNCTION_LOOP));
+        assert_eq!(keymap.action(2), KeyAction::Function(FUNCTION_LOOP_STOP));
+    }
+
+    #[test]
+    fn sustain_key_and_midi_cc64_act_as_pedal() {
+        static LAYERS: [[KeyAction; 21]; 1] = {
+            let mut layer = keyboard_matrix::KIB_BASE_LAYER;
+            layer[0] = KeyAction::Function(FUNCTION_SUSTAIN);
+            [layer]
+        };
+
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.state.keymap = keyboard_matrix::Keymap::new(&LAYERS);
+
+        press(&mut keyboard_state, 0);
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        release(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Sustain);
+
+        synth_engine.receive_midi_bytes(&[0xB0, 64, 127]);
+        release(&mut keyboard_state, 0);
+        synth_engine.update(0, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Sustain);
+
+        synth_engine.receive_midi_bytes(&[0xB0, 64, 0]);
+        synth_engine.update(0, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Release);
+    }
+
+    #[test]
+    fn arpeggiator_steps_through_held_keys() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut buffer = [0; 8];
+
+        synth_engine.state.arpeggiator.enabled = true;
+        synth_engine.state.arpeggiator.order = ArpOrder::Down;
+        let step_ms = synth_engine.state.arpeggiator.step_ms();
+
+        press(&mut keyboard_state, 13);
+        press(&mut keyboard_state, 14);
+        synth_engine.update(0, &keyboard_state);
+
+        assert!(synth_engine.state.note_index_state[38] == NoteState::Pressed);
+        assert!(!synth_engine.state.note_index_state[36].is_active());
+        assert_eq!(synth_engine.state.arpeggiated_keys(), [14].into_iter().collect());
+        assert_eq!(synth_engine.write_midi(&mut buffer), 3);
+        assert_eq!(buffer[..3], [0x90, 62, 100]);
+
+        synth_engine.update(step_ms, &keyboard_state);
+
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Pressed);
+        assert!(synth_engine.state.note_index_state[38] == NoteState::Release);
+        assert_eq!(synth_engine.state.arpeggiated_keys(), [13].into_iter().collect());
+        assert_eq!(synth_engine.write_midi(&mut buffer), 6);
+        assert_eq!(buffer[..6], [0x80, 62, 0x40, 0x90, 60, 100]);
+    }
+
+    fn first_notes(note_indices: impl Iterator<Item = usize>) -> [usize; 8] {
+        let mut notes = [0; 8];
+
+        for (note, note_index) in notes.iter_mut().zip(note_indices) {
+            *note = note_index;
+        }
+
+        notes
+    }
+
+    fn active_notes(synth_engine: &SynthEngine) -> [usize; 8] {
+        let note_index_state = &synth_engine.state.note_index_state;
+
+        first_notes((0..crate::NUM_NOTES).filter(|note_index| note_index_state[*note_index].is_active()))
+    }
+
+    #[test]
+    fn chord_mode_plays_chord_on_key() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut buffer = [0; 12];
+
+        synth_engine.set_chord(Some(ChordShape::MINOR));
+        press(&mut keyboard_state, 14);
+        synth_engine.update(0, &keyboard_state);
+
+        assert_eq!(active_notes(&synth_engine)[..4], [38, 41, 45, 0]);
+        assert_eq!(synth_engine.write_midi(&mut buffer), 9);
+        assert_eq!(buffer[..9], [0x90, 62, 100, 0x90, 65, 100, 0x90, 69, 100]);
+    }
+
+    #[test]
+    fn diatonic_chords_follow_scale() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_scale(Scale::MAJOR);
+        synth_engine.set_chord(Some(ChordShape::SEVENTH));
+        synth_engine.set_chord_diatonic(true);
+
+        // Key 12 plays the second degree, D minor seventh in C major
+        press(&mut keyboard_state, 12);
+        synth_engine.update(0, &keyboard_state);
+
+        assert_eq!(active_notes(&synth_engine)[..4], [38, 41, 45, 48]);
+    }
+
+    #[test]
+    fn diatonic_custom_chord_snaps_down_to_scale() {
+        let mut synth_engine = SynthEngine::new();
+
+        synth_engine.set_scale(Scale::MAJOR);
+        synth_engine.set_chord(Some(ChordShape::custom(&[0, 4, 6]).unwrap()));
+        synth_engine.set_chord_diatonic(true);
+
+        // On E the major third G# snaps to G and the tritone A# to A
+        let notes = synth_engine.state.key_notes(2);
+
+        assert_eq!(first_notes(notes.iter())[..4], [40, 43, 45, 0]);
+    }
+
+    #[test]
+    fn diatonic_power_chord_keeps_fifth_and_octave_in_short_scales() {
+        let mut synth_engine = SynthEngine::new();
+
+        synth_engine.set_scale(Scale::PENTATONIC);
+        synth_engine.set_chord(Some(ChordShape::POWER));
+        synth_engine.set_chord_diatonic(true);
+
+        let notes = synth_engine.state.key_notes(0);
+
+        assert_eq!(first_notes(notes.iter())[..4], [36, 43, 48, 0]);
+    }
+
+    #[test]
+    fn latched_chord_toggles_as_whole() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_latch(true);
+        synth_engine.set_chord(Some(ChordShape::POWER));
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        release(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+
+        assert_eq!(active_notes(&synth_engine)[..4], [36, 43, 48, 0]);
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        release(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+
+        assert!(synth_engine.state.latched_notes.is_empty());
+        assert_eq!(active_notes(&synth_engine)[0], 0);
+    }
+
+    #[test]
+    fn voice_limit_steals_note_and_sends_note_off() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut buffer = [0; 16];
+
+        synth_engine.state.voices.enabled = true;
+        synth_engine.state.voices.policy = StealPolicy::Lowest;
+        synth_engine.state.voices.set_voice_count(2);
+
+        synth_engine.set_chord(Some(ChordShape::MAJOR));
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+
+        // The root lost its voice to the fifth
+        assert_eq!(active_notes(&synth_engine)[..3], [40, 43, 0]);
+        assert_eq!(synth_engine.write_midi(&mut buffer), 6);
+        assert_eq!(buffer[..6], [0x90, 64, 100, 0x90, 67, 100]);
+
+        let events = synth_engine.state.voices.events();
+        assert_eq!(events.pop(), Some(VoiceEvent::Assign { voice: 0, note: 60 }));
+        assert_eq!(events.pop(), Some(VoiceEvent::Assign { voice: 1, note: 64 }));
+        assert_eq!(events.pop(), Some(VoiceEvent::Steal { voice: 0, stolen_note: 60, note: 67 }));
+    }
+
+    #[test]
+    fn mono_mode_falls_back_and_retriggers() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.state.mono.enabled = true;
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        press(&mut keyboard_state, 15);
+        synth_engine.update(0, &keyboard_state);
+
+        assert_eq!(active_notes(&synth_engine)[..3], [36, 40, 0]);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Release);
+        assert!(synth_engine.state.note_index_state[40] == NoteState::Pressed);
+
+        release(&mut keyboard_state, 15);
+        synth_engine.update(0, &keyboard_state);
+
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Pressed);
+        assert!(synth_engine.state.note_index_state[40] == NoteState::Release);
+    }
+
+    #[test]
+    fn mono_legato_moves_without_retrigger() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.state.mono.enabled = true;
+        synth_engine.state.mono.legato = true;
+        synth_engine.state.mono.priority = NotePriority::High;
+
+        press(&mut keyboard_state, 15);
+        synth_engine.update(0, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[40] == NoteState::Pressed);
+
+        // Lower note is held but the higher keeps priority
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        assert!(!synth_engine.state.note_index_state[36].is_active());
+
+        release(&mut keyboard_state, 15);
+        synth_engine.update(0, &keyboard_state);
+
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Sustain);
+        assert!(synth_engine.state.note_index_state[40] == NoteState::Release);
+    }
+
+    #[test]
+    fn release_lasts_release_ms() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.state.release_ms = 100;
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(10, &keyboard_state);
+        release(&mut keyboard_state, 13);
+        synth_engine.update(10, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Release);
+
+        synth_engine.update(60, &keyboard_state);
+        synth_engine.update(39, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Release);
+        assert_eq!(synth_engine.state.note_state_ms(36), 99);
+
+        synth_engine.update(1, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Off);
+        assert!(synth_engine.state.dirty);
+    }
+
+    #[test]
+    fn attack_holds_pressed_for_attack_ms() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.state.attack_ms = 20;
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(10, &keyboard_state);
+        synth_engine.update(10, &keyboard_state);
+        synth_engine.update(9, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Pressed);
+
+        synth_engine.update(1, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Sustain);
+    }
+
+    #[test]
+    fn pressing_during_timed_release_restarts_the_note() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.state.release_ms = 100;
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(10, &keyboard_state);
+        release(&mut keyboard_state, 13);
+        synth_engine.update(10, &keyboard_state);
+        synth_engine.update(50, &keyboard_state);
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(10, &keyboard_state);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Pressed);
+        assert_eq!(synth_engine.state.note_state_ms(36), 0);
+    }
+
+    #[test]
+    fn loop_keys_record_and_play_back_with_live_notes() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        // The KIB function layer puts loop and loop stop on keys 1 and 2
+        synth_engine.state.keymap.set_layer(1, true);
+        // One bar of four 100ms steps
+        synth_engine.state.looper.set_grid(600, 1);
+        synth_engine.state.looper.set_bars(1);
+
+        press(&mut keyboard_state, 1);
+        synth_engine.update(0, &keyboard_state);
+        release(&mut keyboard_state, 1);
+        assert_eq!(synth_engine.state.looper.state(), LooperState::Recording);
+
+        press(&mut keyboard_state, 13);
+        synth_engine.update(0, &keyboard_state);
+        release(&mut keyboard_state, 13);
+        synth_engine.update(100, &keyboard_state);
+        synth_engine.update(300, &keyboard_state);
+        assert_eq!(synth_engine.state.looper.state(), LooperState::Playing);
+
+        // Loop note sounds and lights its key alongside a live one
+        press(&mut keyboard_state, 15);
+        synth_engine.update(0, &keyboard_state);
+        assert_eq!(active_notes(&synth_engine)[..2], [36, 40]);
+        assert!(synth_engine.state.looped_keys().contains(13));
+
+        press(&mut keyboard_state, 2);
+        synth_engine.update(0, &keyboard_state);
+        assert_eq!(synth_engine.state.looper.state(), LooperState::Stopped);
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Release);
+    }
+
     #[test]
     fn get_octave_notes_with_keys_pressed_returns_correct_notes() {
         let mut synth_engine = SynthEngine::new();
         let mut keyboard_state = keyboard_matrix::KeyboardState::default();
 
-        keyboard_state.state[13] = true;
+        keyboard_state.state.insert(13);
 
-        synth_engine.update(&keyboard_state);
+        synth_engine.update(0, &keyboard_state);
 
         let (octave, octave_notes) = synth_engine.get_octave_notes();
 
@@ -0,0 +1,594 @@
+use crate::NoteSet;
+
+/// Most note on and note off events a loop holds.
+pub const LOOP_EVENTS: usize = 64;
+
+/// Longest loop in bars.
+pub const MAX_LOOP_BARS: u8 = 8;
+
+const BEATS_PER_BAR: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LooperState {
+    Stopped,
+    /// First pass, the loop is recorded from empty and plays once the pass ends.
+    Recording,
+    Playing,
+    /// Plays the loop while recording more notes into it.
+    Overdubbing,
+}
+
+impl LooperState {
+    pub fn from_int(value: u8) -> Option<LooperState> {
+        match value {
+            0 => Some(LooperState::Stopped),
+            1 => Some(LooperState::Recording),
+            2 => Some(LooperState::Playing),
+            3 => Some(LooperState::Overdubbing),
+            _ => None,
+        }
+    }
+
+    pub fn to_int(&self) -> u8 {
+        match self {
+            LooperState::Stopped => 0,
+            LooperState::Recording => 1,
+            LooperState::Playing => 2,
+            LooperState::Overdubbing => 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct LoopEvent {
+    step: u16,
+    note_index: u8,
+    on: bool,
+}
+
+/// Records notes as they are played and loops them back, quantised to a grid of `subdivision` steps per beat
+/// over `bars` bars of 4/4.
+///
+/// Notes still held when recording stops are ended there, so a full buffer or a stop never leaves a note
+/// hanging.  A note always lasts at least one step.
+pub struct Looper {
+    bpm: u16,
+    // Grid steps per beat, 4 for sixteenth notes
+    subdivision: u8,
+    state: LooperState,
+    bars: u8,
+    events: [LoopEvent; LOOP_EVENTS],
+    event_count: usize,
+    dropped: usize,
+    position_ms: u32,
+    // Live notes as of the last update, changes from them are recorded
+    live: NoteSet,
+    // Recorded notes with no note off yet
+    open: NoteSet,
+    playing: NoteSet,
+}
+
+impl Default for Looper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Looper {
+    /// Stopped and empty, two bars at 120 BPM quantised to sixteenth notes.
+    pub fn new() -> Self {
+        Self {
+            bpm: 120,
+            subdivision: 4,
+
+            state: LooperState::Stopped,
+            bars: 2,
+            events: [LoopEvent::default(); LOOP_EVENTS],
+            event_count: 0,
+            dropped: 0,
+            position_ms: 0,
+            live: NoteSet::EMPTY,
+            open: NoteSet::EMPTY,
+            playing: NoteSet::EMPTY,
+        }
+    }
+
+    pub fn state(&self) -> LooperState {
+        self.state
+    }
+
+    pub fn bars(&self) -> u8 {
+        self.bars
+    }
+
+    pub fn bpm(&self) -> u16 {
+        self.bpm
+    }
+
+    /// Grid steps per beat.
+    pub fn subdivision(&self) -> u8 {
+        self.subdivision
+    }
+
+    /// Sets the tempo and the grid steps per beat, both at least 1.  Recorded notes keep their place in the bar
+    /// on the new grid, a note squeezed onto a single step still lasts one step.
+    pub fn set_grid(&mut self, bpm: u16, subdivision: u8) {
+        let old_subdivision = self.subdivision as u32;
+        let old_loop_ms = self.loop_ms();
+
+        self.bpm = bpm.max(1);
+        self.subdivision = subdivision.max(1);
+
+        let loop_steps = self.loop_steps();
+        self.position_ms = (self.position_ms as u64 * self.loop_ms() as u64 / old_loop_ms as u64) as u32;
+
+        for index in 0..self.event_count {
+            let mut event = self.events[index];
+            event.step = (event.step as u32 * self.subdivision as u32 / old_subdivision) as u16;
+
+            if !event.on {
+                let on_step = self.events[..index]
+                    .iter()
+                    .rev()
+                    .find(|earlier| earlier.note_index == event.note_index && earlier.on)
+                    .map(|earlier| earlier.step);
+
+                if on_step == Some(event.step) {
+                    event.step = ((event.step as u32 + 1) % loop_steps) as u16;
+                }
+            }
+
+            self.events[index] = event;
+        }
+    }
+
+    /// Clamped to 1 - `MAX_LOOP_BARS`.  Shortening the loop drops notes starting past its new end and ends
+    /// notes running past it on its last step.
+    pub fn set_bars(&mut self, bars: u8) {
+        self.bars = bars.clamp(1, MAX_LOOP_BARS);
+        self.position_ms %= self.loop_ms();
+
+        let loop_steps = self.loop_steps();
+        let last_step = (loop_steps - 1) as u16;
+        // Notes whose latest note on was dropped, their note off goes with it
+        let mut dropped = NoteSet::EMPTY;
+        let mut kept = 0;
+
+        for index in 0..self.event_count {
+            let mut event = self.events[index];
+            let note_index = event.note_index as usize;
+
+            if event.on {
+                dropped.set(note_index, event.step as u32 >= loop_steps);
+            } else if dropped.contains(note_index) {
+                dropped.remove(note_index);
+                continue;
+            } else if event.step as u32 >= loop_steps {
+                let on_step = self.events[..kept]
+                    .iter()
+                    .rev()
+                    .find(|kept| kept.note_index == event.note_index && kept.on)
+                    .map(|kept| kept.step);
+
+                // Still lasts at least one step
+                event.step = if on_step == Some(last_step) { 0 } else { last_step };
+            }
+
+            if event.on && dropped.contains(note_index) {
+                continue;
+            }
+
+            self.events[kept] = event;
+            kept += 1;
+        }
+
+        self.event_count = kept;
+        // Notes still being recorded lost their note on
+        self.open = self.open.difference(dropped);
+
+        // Nothing would end loop notes whose note off was dropped
+        let playing = self.playing;
+        for note_index in playing.iter() {
+            if !self.events[..kept].iter().any(|event| event.note_index as usize == note_index && !event.on) {
+                self.playing.remove(note_index);
+            }
+        }
+    }
+
+    pub fn step_ms(&self) -> u32 {
+        let steps_per_minute = self.bpm.max(1) as u32 * self.subdivision.max(1) as u32;
+
+        (60_000 / steps_per_minute).max(1)
+    }
+
+    pub fn loop_steps(&self) -> u32 {
+        self.bars as u32 * BEATS_PER_BAR * self.subdivision.max(1) as u32
+    }
+
+    fn loop_ms(&self) -> u32 {
+        self.loop_steps() * self.step_ms()
+    }
+
+    pub fn event_count(&self) -> usize {
+        self.event_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.event_count == 0
+    }
+
+    /// Notes not recorded since the last call because the buffer was full.
+    pub fn take_dropped(&mut self) -> usize {
+        core::mem::take(&mut self.dropped)
+    }
+
+    /// Loop notes sounding now.
+    pub fn notes(&self) -> NoteSet {
+        self.playing
+    }
+
+    /// Starts recording a new loop from the beginning, replacing any recorded one.
+    pub fn record(&mut self) {
+        self.clear();
+        self.state = LooperState::Recording;
+    }
+
+    /// Plays the loop, ending any recording.  Starts from the beginning when stopped.
+    pub fn play(&mut self) {
+        self.close_recording();
+
+        if self.state == LooperState::Stopped {
+            self.restart();
+        }
+
+        self.state = LooperState::Playing;
+    }
+
+    /// Records more notes into the loop while playing it.  Starts from the beginning when stopped.
+    pub fn overdub(&mut self) {
+        if self.state == LooperState::Stopped {
+            self.restart();
+        }
+
+        // Notes already held are recorded from here
+        self.live = NoteSet::EMPTY;
+        self.state = LooperState::Overdubbing;
+    }
+
+    /// Stops playing and recording, keeping the loop.
+    pub fn stop(&mut self) {
+        self.close_recording();
+
+        self.state = LooperState::Stopped;
+        self.playing = NoteSet::EMPTY;
+    }
+
+    /// Stops and empties the loop.
+    pub fn clear(&mut self) {
+        self.stop();
+
+        self.event_count = 0;
+        self.position_ms = 0;
+        self.live = NoteSet::EMPTY;
+    }
+
+    /// One button control: record when empty, then cycle between playing and overdubbing.
+    pub fn cycle(&mut self) {
+        match self.state {
+            LooperState::Stopped if self.is_empty() => self.record(),
+            LooperState::Stopped | LooperState::Recording | LooperState::Overdubbing => self.play(),
+            LooperState::Playing => self.overdub(),
+        }
+    }
+
+    /// Stops, or clears the loop when already stopped.
+    pub fn stop_or_clear(&mut self) {
+        if self.state == LooperState::Stopped {
+            self.clear();
+        } else {
+            self.stop();
+        }
+    }
+
+    /// Advances by `delta_t_ms`, recording changes in the `live` notes, and returns the loop notes to sound.
+    pub fn update(&mut self, delta_t_ms: u32, live: &NoteSet) -> NoteSet {
+        if self.state == LooperState::Stopped {
+            self.live = *live;
+            return NoteSet::EMPTY;
+        }
+
+        let step_ms = self.step_ms();
+        let loop_steps = self.loop_steps();
+        let previous_step = self.position_ms / step_ms;
+
+        self.position_ms += delta_t_ms;
+
+        if matches!(self.state, LooperState::Recording | LooperState::Overdubbing) {
+            for note_index in live.difference(self.live).iter() {
+                self.record_event(note_index as u8, true);
+            }
+            for note_index in self.live.difference(*live).iter() {
+                self.record_event(note_index as u8, false);
+            }
+        }
+        self.live = *live;
+
+        if self.state == LooperState::Recording && self.position_ms >= self.loop_ms() {
+            // First pass over, held notes end at the loop's end and it plays from the start
+            self.close_recording();
+            self.state = LooperState::Playing;
+        }
+
+        if self.state != LooperState::Recording {
+            let current_step = self.position_ms / step_ms;
+            let crossed = (current_step.saturating_sub(previous_step)).min(loop_steps);
+
+            for step in previous_step + 1..=previous_step + crossed {
+                self.play_step((step % loop_steps) as u16);
+            }
+        }
+
+        self.position_ms %= self.loop_ms();
+
+        self.playing
+    }
+
+    fn restart(&mut self) {
+        self.position_ms = 0;
+        self.playing = NoteSet::EMPTY;
+        self.play_step(0);
+    }
+
+    fn play_step(&mut self, step: u16) {
+        // Offs first so a note ended and restarted on the same step keeps playing
+        for on in [false, true] {
+            let events = self.events[..self.event_count].iter();
+
+            for event in events.filter(|event| event.step == step && event.on == on) {
+                self.playing.set(event.note_index as usize, on);
+            }
+        }
+    }
+
+    /// Ends every recorded note still held.
+    fn close_recording(&mut self) {
+        if matches!(self.state, LooperState::Recording | LooperState::Overdubbing) {
+            let open = self.open;
+            for note_index in open.iter() {
+                self.record_event(note_index as u8, false);
+            }
+        }
+    }
+
+    fn record_event(&mut self, note_index: u8, on: bool) {
+        let step_ms = self.step_ms();
+        let loop_steps = self.loop_steps();
+        let mut step = ((self.position_ms + step_ms / 2) / step_ms) % loop_steps;
+
+        if on {
+            // Keep room for the note off of every open note, this one included
+            if self.event_count + self.open.iter().count() + 2 > LOOP_EVENTS {
+                self.dropped += 1;
+                return;
+            }
+
+            self.open.insert(note_index as usize);
+        } else {
+            if !self.open.contains(note_index as usize) {
+                return;
+            }
+
+            self.open.remove(note_index as usize);
+
+            let on_step = self.events[..self.event_count]
+                .iter()
+                .rev()
+                .find(|event| event.note_index == note_index && event.on)
+                .map(|event| event.step as u32);
+
+            if on_step == Some(step) {
+                step = (step + 1) % loop_steps;
+            }
+        }
+
+        self.events[self.event_count] = LoopEvent { step: step as u16, note_index, on };
+        self.event_count += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Looper, LooperState, NoteSet, LOOP_EVENTS};
+
+    fn notes(note_indices: &[usize]) -> NoteSet {
+        let mut notes = NoteSet::EMPTY;
+        for note_index in note_indices {
+            notes.insert(*note_index);
+        }
+        notes
+    }
+
+    /// One bar of 4 steps, 100ms each.
+    fn looper() -> Looper {
+        let mut looper = Looper::new();
+        looper.set_grid(600, 1);
+        looper.set_bars(1);
+        looper
+    }
+
+    #[test]
+    fn records_and_loops_notes_quantised_to_the_grid() {
+        let mut looper = looper();
+        assert_eq!(looper.step_ms(), 100);
+
+        looper.record();
+        // Played slightly late and early, lands on steps 1 and 2
+        looper.update(120, &notes(&[36]));
+        looper.update(70, &NoteSet::EMPTY);
+        looper.update(200, &NoteSet::EMPTY);
+        assert_eq!(looper.state(), LooperState::Recording);
+
+        looper.update(10, &NoteSet::EMPTY);
+        assert_eq!(looper.state(), LooperState::Playing);
+        assert!(looper.notes().is_empty());
+
+        assert!(looper.update(99, &NoteSet::EMPTY).is_empty());
+        assert!(looper.update(1, &NoteSet::EMPTY) == notes(&[36]));
+        assert!(looper.update(99, &NoteSet::EMPTY) == notes(&[36]));
+        assert!(looper.update(1, &NoteSet::EMPTY).is_empty());
+
+        // And again next time round
+        looper.update(200, &NoteSet::EMPTY);
+        assert!(looper.update(100, &NoteSet::EMPTY) == notes(&[36]));
+    }
+
+    #[test]
+    fn overdub_adds_to_the_loop() {
+        let mut looper = looper();
+
+        looper.record();
+        looper.update(0, &notes(&[36]));
+        looper.update(100, &NoteSet::EMPTY);
+        looper.update(300, &NoteSet::EMPTY);
+
+        looper.overdub();
+        looper.update(200, &notes(&[40]));
+        looper.update(100, &NoteSet::EMPTY);
+        looper.play();
+        assert_eq!(looper.event_count(), 4);
+
+        assert!(looper.update(100, &NoteSet::EMPTY) == notes(&[36]));
+        assert!(looper.update(100, &NoteSet::EMPTY).is_empty());
+        assert!(looper.update(100, &NoteSet::EMPTY) == notes(&[40]));
+        assert!(looper.update(100, &NoteSet::EMPTY).is_empty());
+    }
+
+    #[test]
+    fn notes_held_at_the_end_of_recording_are_ended() {
+        let mut looper = looper();
+
+        looper.record();
+        looper.update(200, &notes(&[36]));
+        looper.play();
+
+        assert_eq!(looper.event_count(), 2);
+        assert!(looper.update(200, &notes(&[36])).is_empty());
+
+        // Tapped on a single step still lasts one step
+        looper.overdub();
+        looper.update(0, &notes(&[40]));
+        looper.update(20, &NoteSet::EMPTY);
+        looper.stop();
+        looper.play();
+        assert!(looper.notes() == notes(&[40]));
+        assert!(looper.update(100, &NoteSet::EMPTY).is_empty());
+    }
+
+    #[test]
+    fn full_buffer_drops_notes_without_leaving_them_hanging() {
+        let mut looper = looper();
+        looper.set_bars(8);
+
+        looper.record();
+        for note_index in 0..LOOP_EVENTS {
+            looper.update(0, &notes(&[note_index]));
+        }
+        looper.play();
+
+        assert_eq!(looper.event_count(), LOOP_EVENTS);
+        assert_eq!(looper.take_dropped(), LOOP_EVENTS / 2);
+        assert_eq!(looper.take_dropped(), 0);
+    }
+
+    #[test]
+    fn shortening_the_loop_ends_notes_past_its_end() {
+        let mut looper = looper();
+        looper.set_bars(2);
+
+        // 36 on steps 2 - 7, 40 on steps 5 - 6
+        looper.record();
+        looper.update(200, &notes(&[36]));
+        looper.update(300, &notes(&[36, 40]));
+        looper.update(100, &notes(&[36]));
+        looper.update(100, &NoteSet::EMPTY);
+        looper.update(100, &NoteSet::EMPTY);
+        assert_eq!(looper.state(), LooperState::Playing);
+        assert_eq!(looper.event_count(), 4);
+
+        assert!(looper.update(500, &NoteSet::EMPTY) == notes(&[36, 40]));
+
+        // Back to step 1 of 4, 40 is gone and 36 ends on the last step
+        looper.set_bars(1);
+        assert_eq!(looper.event_count(), 2);
+        assert!(looper.notes() == notes(&[36]));
+
+        assert!(looper.update(100, &NoteSet::EMPTY) == notes(&[36]));
+        assert!(looper.update(100, &NoteSet::EMPTY).is_empty());
+        assert!(looper.update(100, &NoteSet::EMPTY).is_empty());
+        assert!(looper.update(200, &NoteSet::EMPTY) == notes(&[36]));
+        assert!(looper.update(100, &NoteSet::EMPTY).is_empty());
+    }
+
+    #[test]
+    fn coarser_grid_keeps_notes_ending() {
+        let mut looper = Looper::new();
+        looper.set_grid(150, 4);
+        assert_eq!(looper.step_ms(), 100);
+
+        // 36 on steps 6 - 10 of 32
+        looper.record();
+        looper.update(600, &notes(&[36]));
+        looper.update(400, &NoteSet::EMPTY);
+        looper.update(2200, &NoteSet::EMPTY);
+        assert_eq!(looper.state(), LooperState::Playing);
+
+        // Now on step 1 - 2 of 8, 400ms each
+        looper.set_grid(150, 1);
+        looper.set_bars(2);
+        assert_eq!(looper.loop_steps(), 8);
+
+        for _ in 0..2 {
+            assert!(looper.update(500, &NoteSet::EMPTY) == notes(&[36]));
+            assert!(looper.update(400, &NoteSet::EMPTY).is_empty());
+            assert!(looper.update(2300, &NoteSet::EMPTY).is_empty());
+        }
+    }
+
+    #[test]
+    fn squeezed_notes_still_last_a_step() {
+        let mut looper = Looper::new();
+        looper.set_grid(150, 4);
+        looper.set_bars(1);
+
+        // 36 on steps 1 - 2 of 16, both on step 0 of 4 once coarser
+        looper.record();
+        looper.update(100, &notes(&[36]));
+        looper.update(100, &NoteSet::EMPTY);
+        looper.update(1400, &NoteSet::EMPTY);
+
+        looper.set_grid(150, 1);
+        assert!(looper.notes().is_empty());
+        assert!(looper.update(400, &NoteSet::EMPTY).is_empty());
+        assert!(looper.update(1200, &NoteSet::EMPTY) == notes(&[36]));
+        assert!(looper.update(400, &NoteSet::EMPTY).is_empty());
+    }
+
+    #[test]
+    fn cycle_and_stop_or_clear() {
+        let mut looper = looper();
+
+        looper.cycle();
+        assert_eq!(looper.state(), LooperState::Recording);
+        looper.update(0, &notes(&[36]));
+        looper.cycle();
+        assert_eq!(looper.state(), LooperState::Playing);
+        looper.cycle();
+        assert_eq!(looper.state(), LooperState::Overdubbing);
+
+        looper.stop_or_clear();
+        assert_eq!(looper.state(), LooperState::Stopped);
+        assert!(!looper.is_empty());
+        looper.stop_or_clear();
+        assert!(looper.is_empty());
+    }
+}
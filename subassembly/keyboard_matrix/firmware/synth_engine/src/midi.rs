@@ -0,0 +1,260 @@
+use crate::{NoteSet, NoteState, SynthState, NUM_NOTES};
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+
+/// Longest MIDI message written, in bytes.
+pub const MIDI_MESSAGE_MAX: usize = 3;
+
+/// Turns note state transitions into MIDI Note On and Note Off messages.
+///
+/// Notes that change between calls to `write` are sent, Note Offs first.  Messages that do not fit in the
+/// buffer stay pending for the next call, so the buffer can be any size from `MIDI_MESSAGE_MAX` up.
+pub struct MidiOutput {
+    /// 0-15, shown as channel 1-16 by most devices.
+    pub channel: u8,
+    /// Note On velocity 1-127.
+    pub velocity: u8,
+
+    running_status: bool,
+    last_status: Option<u8>,
+    // Notes with a Note On sent and no Note Off yet
+    sounding: NoteSet,
+}
+
+impl Default for MidiOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MidiOutput {
+    /// Channel 1, velocity 100, running status off.
+    pub fn new() -> Self {
+        Self {
+            channel: 0,
+            velocity: 100,
+
+            running_status: false,
+            last_status: None,
+            sounding: NoteSet::EMPTY,
+        }
+    }
+
+    /// With running status the status byte is left out when it repeats, and Note Offs are sent as Note On
+    /// with velocity 0 so a stream of notes shares a single status byte.
+    pub fn set_running_status(&mut self, enabled: bool) {
+        self.running_status = enabled;
+        self.last_status = None;
+    }
+
+    /// Forces the next message to carry its status byte, e.g. after the transport dropped bytes.
+    pub fn reset_running_status(&mut self) {
+        self.last_status = None;
+    }
+
+    pub fn is_sounding(&self, note_index: usize) -> bool {
+        self.sounding.contains(note_index)
+    }
+
+    /// Writes the messages for notes that changed since the last call, returning the bytes written.
+    pub fn write(&mut self, synth_state: &SynthState, buffer: &mut [u8]) -> usize {
+        let mut written = 0;
+
+        // Note Offs first so a moved note never has two copies sounding, except for mono legato where the
+        // overlap tells a mono synth not to retrigger
+        let order = if synth_state.mono.enabled && synth_state.mono.legato {
+            [true, false]
+        } else {
+            [false, true]
+        };
+
+        for note_on in order {
+            for note_index in 0..NUM_NOTES {
+                let active = matches!(
+                    synth_state.note_index_state[note_index],
+                    NoteState::Pressed | NoteState::Sustain
+                );
+
+                if active != note_on || self.is_sounding(note_index) == note_on {
+                    continue;
+                }
+
+                match self.write_note(note_index, note_on, &mut buffer[written..]) {
+                    Some(length) => written += length,
+                    None => return written,
+                }
+
+                self.sounding.set(note_index, note_on);
+            }
+        }
+
+        written
+    }
+
+    /// Writes Note Offs for every sounding note, for use before the engine stops updating.
+    pub fn write_all_off(&mut self, buffer: &mut [u8]) -> usize {
+        let mut written = 0;
+
+        for note_index in 0..NUM_NOTES {
+            if !self.is_sounding(note_index) {
+                continue;
+            }
+
+            match self.write_note(note_index, false, &mut buffer[written..]) {
+                Some(length) => written += length,
+                None => return written,
+            }
+
+            self.sounding.remove(note_index);
+        }
+
+        written
+    }
+
+    fn write_note(&mut self, note_index: usize, note_on: bool, buffer: &mut [u8]) -> Option<usize> {
+        let note = SynthState::note_index_to_midi(note_index as u8);
+
+        let (status, velocity) = match (note_on, self.running_status) {
+            (true, _) => (NOTE_ON, self.velocity),
+            (false, true) => (NOTE_ON, 0),
+            (false, false) => (NOTE_OFF, 0x40),
+        };
+        let status = status | (self.channel & 0x0F);
+
+        let skip_status = self.running_status && self.last_status == Some(status);
+        let length = if skip_status { 2 } else { 3 };
+
+        if buffer.len() < length {
+            return None;
+        }
+
+        let message = [status, note & 0x7F, velocity & 0x7F];
+        buffer[..length].copy_from_slice(&message[3 - length..]);
+
+        self.last_status = Some(status);
+
+        Some(length)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{MidiOutput, NoteState, SynthState};
+
+    #[test]
+    fn pressed_note_sends_note_on() {
+        let mut midi = MidiOutput::new();
+        let mut synth_state = SynthState::new();
+        let mut buffer = [0; 8];
+
+        synth_state.note_index_state[36] = NoteState::Pressed;
+
+        assert_eq!(midi.write(&synth_state, &mut buffer), 3);
+        assert_eq!(buffer[..3], [0x90, 60, 100]);
+
+        synth_state.note_index_state[36] = NoteState::Sustain;
+        assert_eq!(midi.write(&synth_state, &mut buffer), 0);
+    }
+
+    #[test]
+    fn released_note_sends_note_off_on_channel() {
+        let mut midi = MidiOutput::new();
+        let mut synth_state = SynthState::new();
+        let mut buffer = [0; 8];
+
+        midi.channel = 9;
+        synth_state.note_index_state[36] = NoteState::Pressed;
+        midi.write(&synth_state, &mut buffer);
+
+        synth_state.note_index_state[36] = NoteState::Release;
+
+        assert_eq!(midi.write(&synth_state, &mut buffer), 3);
+        assert_eq!(buffer[..3], [0x89, 60, 0x40]);
+        assert!(!midi.is_sounding(36));
+    }
+
+    #[test]
+    fn running_status_omits_repeated_status() {
+        let mut midi = MidiOutput::new();
+        let mut synth_state = SynthState::new();
+        let mut buffer = [0; 16];
+
+        midi.set_running_status(true);
+        synth_state.note_index_state[36] = NoteState::Pressed;
+        synth_state.note_index_state[40] = NoteState::Pressed;
+        midi.write(&synth_state, &mut buffer);
+
+        synth_state.note_index_state[36] = NoteState::Release;
+        synth_state.note_index_state[43] = NoteState::Pressed;
+
+        assert_eq!(midi.write(&synth_state, &mut buffer), 4);
+        assert_eq!(buffer[..4], [60, 0, 67, 100]);
+    }
+
+    #[test]
+    fn note_offs_are_written_before_note_ons() {
+        let mut midi = MidiOutput::new();
+        let mut synth_state = SynthState::new();
+        let mut buffer = [0; 16];
+
+        synth_state.note_index_state[40] = NoteState::Pressed;
+        midi.write(&synth_state, &mut buffer);
+
+        synth_state.note_index_state[40] = NoteState::Release;
+        synth_state.note_index_state[36] = NoteState::Pressed;
+
+        assert_eq!(midi.write(&synth_state, &mut buffer), 6);
+        assert_eq!(buffer[..6], [0x80, 64, 0x40, 0x90, 60, 100]);
+    }
+
+    #[test]
+    fn mono_legato_overlaps_note_on_before_note_off() {
+        let mut midi = MidiOutput::new();
+        let mut synth_state = SynthState::new();
+        let mut buffer = [0; 16];
+
+        synth_state.mono.enabled = true;
+        synth_state.mono.legato = true;
+        synth_state.note_index_state[36] = NoteState::Pressed;
+        midi.write(&synth_state, &mut buffer);
+
+        synth_state.note_index_state[36] = NoteState::Release;
+        synth_state.note_index_state[40] = NoteState::Sustain;
+
+        assert_eq!(midi.write(&synth_state, &mut buffer), 6);
+        assert_eq!(buffer[..6], [0x90, 64, 100, 0x80, 60, 0x40]);
+    }
+
+    #[test]
+    fn messages_that_do_not_fit_stay_pending() {
+        let mut midi = MidiOutput::new();
+        let mut synth_state = SynthState::new();
+        let mut buffer = [0; 4];
+
+        synth_state.note_index_state[36] = NoteState::Pressed;
+        synth_state.note_index_state[38] = NoteState::Pressed;
+
+        assert_eq!(midi.write(&synth_state, &mut buffer), 3);
+        assert_eq!(buffer[..3], [0x90, 60, 100]);
+
+        assert_eq!(midi.write(&synth_state, &mut buffer), 3);
+        assert_eq!(buffer[..3], [0x90, 62, 100]);
+
+        assert_eq!(midi.write(&synth_state, &mut buffer), 0);
+    }
+
+    #[test]
+    fn all_off_silences_sounding_notes() {
+        let mut midi = MidiOutput::new();
+        let mut synth_state = SynthState::new();
+        let mut buffer = [0; 8];
+
+        synth_state.note_index_state[0] = NoteState::Pressed;
+        midi.write(&synth_state, &mut buffer);
+
+        assert_eq!(midi.write_all_off(&mut buffer), 3);
+        assert_eq!(buffer[..3], [0x80, 24, 0x40]);
+        assert!(!midi.is_sounding(0));
+    }
+}
@@ -0,0 +1,181 @@
+/// A parsed MIDI message.  Channels are 0-15.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiMessage {
+    /// Note On with velocity 0 is reported as Note Off.
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    /// Other channel messages: program change, pressure and pitch bend.
+    Channel { status: u8, data: [u8; 2] },
+    /// Single byte realtime messages 0xF8-0xFF, e.g. clock and start/stop.
+    Realtime(u8),
+}
+
+/// Sustain pedal, down for values 64 and above.
+pub const CC_SUSTAIN: u8 = 64;
+
+/// Control Change numbers that silence every note.
+pub const CC_ALL_SOUND_OFF: u8 = 120;
+pub const CC_ALL_NOTES_OFF: u8 = 123;
+
+/// Streaming MIDI parser, fed one byte at a time.
+///
+/// Handles running status, skips SysEx and system common messages, and passes realtime bytes through even
+/// when they arrive in the middle of another message.
+#[derive(Default)]
+pub struct MidiParser {
+    status: Option<u8>,
+    data: [u8; 2],
+    data_count: usize,
+    in_sysex: bool,
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a message once `byte` completes one.
+    pub fn parse(&mut self, byte: u8) -> Option<MidiMessage> {
+        match byte {
+            0xF8..=0xFF => Some(MidiMessage::Realtime(byte)),
+            0xF0 => {
+                self.in_sysex = true;
+                self.status = None;
+                None
+            }
+            0xF7 => {
+                self.in_sysex = false;
+                None
+            }
+            0xF1..=0xF6 => {
+                // System common cancels running status, its data bytes are dropped with no status to follow
+                self.in_sysex = false;
+                self.status = None;
+                None
+            }
+            0x80..=0xEF => {
+                self.in_sysex = false;
+                self.status = Some(byte);
+                self.data_count = 0;
+                None
+            }
+            _ => self.parse_data(byte),
+        }
+    }
+
+    fn parse_data(&mut self, byte: u8) -> Option<MidiMessage> {
+        let status = match self.status {
+            Some(status) if !self.in_sysex => status,
+            _ => return None,
+        };
+
+        self.data[self.data_count] = byte;
+        self.data_count += 1;
+
+        let length = match status & 0xF0 {
+            0xC0 | 0xD0 => 1,
+            _ => 2,
+        };
+
+        if self.data_count < length {
+            return None;
+        }
+
+        // Keep the status for running status
+        self.data_count = 0;
+
+        let channel = status & 0x0F;
+        let [first, second] = self.data;
+
+        Some(match status & 0xF0 {
+            0x90 if second > 0 => MidiMessage::NoteOn { channel, note: first, velocity: second },
+            0x80 | 0x90 => MidiMessage::NoteOff { channel, note: first, velocity: second },
+            0xB0 => MidiMessage::ControlChange { channel, controller: first, value: second },
+            _ => MidiMessage::Channel {
+                status,
+                data: [first, if length == 2 { second } else { 0 }],
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{MidiMessage, MidiParser};
+
+    fn parse_all(parser: &mut MidiParser, bytes: &[u8], messages: &mut [Option<MidiMessage>]) -> usize {
+        let mut count = 0;
+
+        for byte in bytes {
+            if let Some(message) = parser.parse(*byte) {
+                messages[count] = Some(message);
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    #[test]
+    fn parses_note_on_and_running_status() {
+        let mut parser = MidiParser::new();
+        let mut messages = [None; 4];
+
+        let count = parse_all(&mut parser, &[0x91, 60, 100, 64, 0], &mut messages);
+
+        assert_eq!(count, 2);
+        assert_eq!(messages[0], Some(MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 }));
+        assert_eq!(messages[1], Some(MidiMessage::NoteOff { channel: 1, note: 64, velocity: 0 }));
+    }
+
+    #[test]
+    fn realtime_inside_message_does_not_break_it() {
+        let mut parser = MidiParser::new();
+        let mut messages = [None; 4];
+
+        let count = parse_all(&mut parser, &[0xB0, 0xF8, 64, 0xFE, 127], &mut messages);
+
+        assert_eq!(count, 3);
+        assert_eq!(messages[0], Some(MidiMessage::Realtime(0xF8)));
+        assert_eq!(messages[1], Some(MidiMessage::Realtime(0xFE)));
+        assert_eq!(messages[2], Some(MidiMessage::ControlChange { channel: 0, controller: 64, value: 127 }));
+    }
+
+    #[test]
+    fn sysex_is_skipped_and_cancels_running_status() {
+        let mut parser = MidiParser::new();
+        let mut messages = [None; 4];
+
+        let count = parse_all(&mut parser, &[0x90, 60, 100, 0xF0, 0x7E, 60, 100, 0xF7, 62, 100], &mut messages);
+
+        assert_eq!(count, 1);
+
+        let count = parse_all(&mut parser, &[0x80, 60, 64], &mut messages);
+
+        assert_eq!(count, 1);
+        assert_eq!(messages[0], Some(MidiMessage::NoteOff { channel: 0, note: 60, velocity: 64 }));
+    }
+
+    #[test]
+    fn one_data_byte_messages() {
+        let mut parser = MidiParser::new();
+        let mut messages = [None; 4];
+
+        let count = parse_all(&mut parser, &[0xC2, 5, 6], &mut messages);
+
+        assert_eq!(count, 2);
+        assert_eq!(messages[1], Some(MidiMessage::Channel { status: 0xC2, data: [6, 0] }));
+    }
+
+    #[test]
+    fn data_without_status_is_ignored() {
+        let mut parser = MidiParser::new();
+
+        assert_eq!(parser.parse(60), None);
+        assert_eq!(parser.parse(100), None);
+        assert_eq!(parser.parse(0xF2), None);
+        assert_eq!(parser.parse(1), None);
+        assert_eq!(parser.parse(2), None);
+    }
+}
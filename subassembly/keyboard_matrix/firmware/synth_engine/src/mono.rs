@@ -0,0 +1,167 @@
+use crate::{NoteOrder, NoteSet};
+
+/// Which held note plays in mono mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High,
+}
+
+impl NotePriority {
+    pub fn from_int(value: u8) -> Option<NotePriority> {
+        match value {
+            0 => Some(NotePriority::Last),
+            1 => Some(NotePriority::Low),
+            2 => Some(NotePriority::High),
+            _ => None,
+        }
+    }
+
+    pub fn to_int(&self) -> u8 {
+        match self {
+            NotePriority::Last => 0,
+            NotePriority::Low => 1,
+            NotePriority::High => 2,
+        }
+    }
+}
+
+/// Plays one held note at a time, falling back to the remaining held notes as keys are released.
+pub struct MonoMode {
+    pub enabled: bool,
+    pub priority: NotePriority,
+    /// Moving between held notes does not retrigger the envelope.
+    pub legato: bool,
+    /// Glide time between notes for downstream synths, 0 for none.
+    pub portamento_ms: u16,
+
+    order: NoteOrder,
+    note: Option<u8>,
+    legato_note: Option<u8>,
+}
+
+impl Default for MonoMode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MonoMode {
+    /// Disabled, last note priority, retriggering without portamento.
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            priority: NotePriority::Last,
+            legato: false,
+            portamento_ms: 0,
+
+            order: NoteOrder::default(),
+            note: None,
+            legato_note: None,
+        }
+    }
+
+    /// The note playing.
+    pub fn note(&self) -> Option<u8> {
+        self.note
+    }
+
+    /// The note playing if the last update moved to it without retriggering.
+    pub fn legato_note(&self) -> Option<u8> {
+        self.legato_note
+    }
+
+    /// Picks the note to play from `held`, returning it as the only note to sound.
+    pub fn update(&mut self, held: &NoteSet) -> NoteSet {
+        self.order.update(held);
+
+        let note = match self.priority {
+            NotePriority::Last => self.order.last(),
+            NotePriority::Low => held.iter().next().map(|note_index| note_index as u8),
+            NotePriority::High => held.iter().last().map(|note_index| note_index as u8),
+        };
+
+        self.legato_note = match (self.note, note) {
+            (Some(previous), Some(note)) if self.legato && previous != note => Some(note),
+            _ => None,
+        };
+        self.note = note;
+
+        let mut notes = NoteSet::EMPTY;
+        if let Some(note_index) = note {
+            notes.insert(note_index as usize);
+        }
+
+        notes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{MonoMode, NotePriority, NoteSet};
+
+    fn notes(note_indices: &[usize]) -> NoteSet {
+        let mut notes = NoteSet::EMPTY;
+        for note_index in note_indices {
+            notes.insert(*note_index);
+        }
+        notes
+    }
+
+    #[test]
+    fn last_note_priority_falls_back_to_previous() {
+        let mut mono = MonoMode::new();
+
+        mono.update(&notes(&[40]));
+        mono.update(&notes(&[40, 36]));
+        assert_eq!(mono.note(), Some(36));
+
+        mono.update(&notes(&[40, 36, 43]));
+        assert_eq!(mono.note(), Some(43));
+
+        mono.update(&notes(&[40, 36]));
+        assert_eq!(mono.note(), Some(36));
+
+        mono.update(&notes(&[40]));
+        assert_eq!(mono.note(), Some(40));
+
+        assert!(mono.update(&NoteSet::EMPTY).is_empty());
+        assert_eq!(mono.note(), None);
+    }
+
+    #[test]
+    fn low_and_high_priority() {
+        let mut mono = MonoMode::new();
+
+        mono.priority = NotePriority::Low;
+        mono.update(&notes(&[40]));
+        mono.update(&notes(&[40, 43]));
+        assert_eq!(mono.note(), Some(40));
+        mono.update(&notes(&[40, 43, 36]));
+        assert_eq!(mono.note(), Some(36));
+
+        mono.priority = NotePriority::High;
+        mono.update(&notes(&[40, 43, 36]));
+        assert_eq!(mono.note(), Some(43));
+    }
+
+    #[test]
+    fn legato_only_between_held_notes() {
+        let mut mono = MonoMode::new();
+        mono.legato = true;
+
+        mono.update(&notes(&[36]));
+        assert_eq!(mono.legato_note(), None);
+
+        mono.update(&notes(&[36, 40]));
+        assert_eq!(mono.legato_note(), Some(40));
+
+        mono.update(&notes(&[36, 40]));
+        assert_eq!(mono.legato_note(), None);
+
+        mono.update(&NoteSet::EMPTY);
+        mono.update(&notes(&[43]));
+        assert_eq!(mono.legato_note(), None);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::NUM_NOTES;
+
+/// A set of note indices stored as a bitmask, note index 0 in the lowest bit of the first word.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct NoteSet([u32; NUM_NOTES.div_ceil(32)]);
+
+impl NoteSet {
+    pub const EMPTY: NoteSet = NoteSet([0; NUM_NOTES.div_ceil(32)]);
+
+    pub fn contains(&self, note_index: usize) -> bool {
+        note_index < NUM_NOTES && self.0[note_index / 32] & (1 << (note_index % 32)) != 0
+    }
+
+    /// Note indices beyond `NUM_NOTES` are ignored.
+    pub fn insert(&mut self, note_index: usize) {
+        if note_index < NUM_NOTES {
+            self.0[note_index / 32] |= 1 << (note_index % 32);
+        }
+    }
+
+    pub fn remove(&mut self, note_index: usize) {
+        if note_index < NUM_NOTES {
+            self.0[note_index / 32] &= !(1 << (note_index % 32));
+        }
+    }
+
+    pub fn set(&mut self, note_index: usize, present: bool) {
+        if present {
+            self.insert(note_index);
+        } else {
+            self.remove(note_index);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::EMPTY;
+    }
+
+    pub fn union(mut self, other: NoteSet) -> NoteSet {
+        for (word, other) in self.0.iter_mut().zip(other.0) {
+            *word |= other;
+        }
+        self
+    }
+
+    pub fn intersection(mut self, other: NoteSet) -> NoteSet {
+        for (word, other) in self.0.iter_mut().zip(other.0) {
+            *word &= other;
+        }
+        self
+    }
+
+    /// Notes in this set but not in `other`.
+    pub fn difference(mut self, other: NoteSet) -> NoteSet {
+        for (word, other) in self.0.iter_mut().zip(other.0) {
+            *word &= !other;
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|word| *word == 0)
+    }
+
+    /// Note indices in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..NUM_NOTES).filter(|note_index| self.contains(*note_index))
+    }
+}
+
+/// Most notes `NoteOrder` remembers, later notes are left out.
+pub const NOTE_ORDER_SIZE: usize = 16;
+
+/// Held notes in the order they were first held.
+#[derive(Default)]
+pub struct NoteOrder {
+    notes: [u8; NOTE_ORDER_SIZE],
+    count: usize,
+}
+
+impl NoteOrder {
+    /// Forgets notes no longer in `held` and appends new ones, lowest first when several arrive together.
+    pub fn update(&mut self, held: &NoteSet) {
+        let mut kept = 0;
+        for index in 0..self.count {
+            if held.contains(self.notes[index] as usize) {
+                self.notes[kept] = self.notes[index];
+                kept += 1;
+            }
+        }
+        self.count = kept;
+
+        for note_index in held.iter().map(|note_index| note_index as u8) {
+            let known = self.notes[..self.count].contains(&note_index);
+
+            if !known && self.count < NOTE_ORDER_SIZE {
+                self.notes[self.count] = note_index;
+                self.count += 1;
+            }
+        }
+    }
+
+    pub fn notes(&self) -> &[u8] {
+        &self.notes[..self.count]
+    }
+
+    /// The most recently held note.
+    pub fn last(&self) -> Option<u8> {
+        self.notes().last().copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{NoteOrder, NoteSet, NUM_NOTES};
+
+    #[test]
+    fn insert_remove_contains() {
+        let mut notes = NoteSet::EMPTY;
+
+        notes.insert(0);
+        notes.insert(40);
+        notes.insert(NUM_NOTES - 1);
+        notes.insert(NUM_NOTES);
+        notes.remove(0);
+
+        assert!(!notes.contains(0));
+        assert!(notes.contains(40));
+        assert!(notes.contains(NUM_NOTES - 1));
+        assert!(!notes.contains(NUM_NOTES));
+        assert_eq!(notes.iter().count(), 2);
+
+        let mut other = NoteSet::EMPTY;
+        other.insert(1);
+        assert_eq!(notes.union(other).iter().count(), 3);
+        assert_eq!(notes.union(other).difference(notes).iter().count(), 1);
+        assert!(notes.union(other).intersection(other) == other);
+
+        notes.clear();
+        assert!(notes.is_empty());
+    }
+
+    #[test]
+    fn note_order_keeps_first_held_order() {
+        let mut order = NoteOrder::default();
+        let mut held = NoteSet::EMPTY;
+
+        held.insert(40);
+        order.update(&held);
+        held.insert(36);
+        held.insert(43);
+        order.update(&held);
+
+        assert_eq!(order.notes(), [40, 36, 43]);
+
+        held.remove(36);
+        order.update(&held);
+
+        assert_eq!(order.notes(), [40, 43]);
+        assert_eq!(order.last(), Some(43));
+    }
+}
@@ -0,0 +1,212 @@
+/// Full scale of a Q15 sample, 1.0.
+pub const Q15_ONE: i32 = 1 << 15;
+
+// Half a cycle of phase
+const HALF_PHASE: u32 = 1 << 31;
+
+// Leak on the triangle integrator so rounding cannot build up a DC offset, about 8 Hz at 32 kHz
+const TRIANGLE_LEAK_SHIFT: u32 = 10;
+
+// Highest note frequencies, C8 - B8 in millihertz, lower octaves halve them
+const TOP_OCTAVE_MILLIHERTZ: [u32; 12] = [
+    4_186_009, 4_434_922, 4_698_636, 4_978_032, 5_274_041, 5_587_652,
+    5_919_911, 6_271_927, 6_644_875, 7_040_000, 7_458_620, 7_902_133,
+];
+
+// MIDI octave of `TOP_OCTAVE_MILLIHERTZ`, C8 is MIDI note 108
+const TOP_OCTAVE: u8 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Saw,
+    Square,
+    Triangle,
+    Sine,
+}
+
+impl Waveform {
+    pub fn from_int(value: u8) -> Option<Waveform> {
+        match value {
+            0 => Some(Waveform::Saw),
+            1 => Some(Waveform::Square),
+            2 => Some(Waveform::Triangle),
+            3 => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+
+    pub fn to_int(&self) -> u8 {
+        match self {
+            Waveform::Saw => 0,
+            Waveform::Square => 1,
+            Waveform::Triangle => 2,
+            Waveform::Sine => 3,
+        }
+    }
+}
+
+/// Frequency of a MIDI note in millihertz, equal tempered with A4 at 440 Hz.
+pub fn midi_note_millihertz(note: u8) -> u32 {
+    let frequency = TOP_OCTAVE_MILLIHERTZ[(note % 12) as usize];
+    let octave = note / 12;
+
+    if octave > TOP_OCTAVE {
+        frequency << (octave - TOP_OCTAVE).min(1)
+    } else {
+        frequency >> (TOP_OCTAVE - octave)
+    }
+}
+
+/// Fixed point oscillator producing Q15 samples from a 32 bit phase accumulator.
+///
+/// Saw and square are band limited with PolyBLEP, the triangle integrates the band limited square and the
+/// sine is a polynomial so no table has to live in flash.
+#[derive(Clone, Copy, Default)]
+pub struct Oscillator {
+    phase: u32,
+    increment: u32,
+    integrator: i32,
+}
+
+impl Oscillator {
+    /// Sets the pitch without resetting the phase, so a running oscillator does not click.
+    pub fn set_frequency(&mut self, millihertz: u32, sample_rate: u32) {
+        let increment = ((millihertz as u64) << 32) / (sample_rate.max(1) as u64 * 1000);
+
+        // Stay below Nyquist where the band limiting no longer holds
+        self.increment = increment.min(HALF_PHASE as u64 - 1) as u32;
+    }
+
+    /// Restarts the cycle from a zero crossing.
+    pub fn reset(&mut self) {
+        self.phase = 0;
+        self.integrator = -Q15_ONE;
+    }
+
+    pub fn next(&mut self, waveform: Waveform) -> i32 {
+        let phase = self.phase;
+        self.phase = phase.wrapping_add(self.increment);
+
+        match waveform {
+            Waveform::Saw => self.saw(phase),
+            Waveform::Square => self.square(phase),
+            Waveform::Triangle => {
+                // A square of +-1 integrated over a cycle at 4 * f / sample rate per sample spans -1 - 1
+                let slope = (self.square(phase) as i64 * self.increment as i64) >> 30;
+
+                self.integrator += slope as i32 - (self.integrator >> TRIANGLE_LEAK_SHIFT);
+                self.integrator.clamp(-Q15_ONE, Q15_ONE)
+            }
+            Waveform::Sine => sine(phase),
+        }
+    }
+
+    fn saw(&self, phase: u32) -> i32 {
+        ((phase >> 16) as i32 - Q15_ONE) - poly_blep(phase, self.increment)
+    }
+
+    fn square(&self, phase: u32) -> i32 {
+        let naive = if phase < HALF_PHASE { Q15_ONE } else { -Q15_ONE };
+
+        naive + poly_blep(phase, self.increment) - poly_blep(phase.wrapping_add(HALF_PHASE), self.increment)
+    }
+}
+
+/// Correction for a unit step at phase 0, spread over the samples either side of it.
+fn poly_blep(phase: u32, increment: u32) -> i32 {
+    if increment == 0 {
+        return 0;
+    }
+
+    if phase < increment {
+        // Just after the step, x in 0 - 1
+        let x = (((phase as u64) << 15) / increment as u64) as i32;
+
+        2 * x - ((x * x) >> 15) - Q15_ONE
+    } else if phase > u32::MAX - increment {
+        // Just before the step, x in -1 - 0
+        let x = -(((((u32::MAX - phase) as u64 + 1) << 15) / increment as u64) as i32);
+
+        ((x * x) >> 15) + 2 * x + Q15_ONE
+    } else {
+        0
+    }
+}
+
+/// Sine of a full cycle of phase, within about 0.1% of full scale.
+fn sine(phase: u32) -> i32 {
+    // Half a cycle either side of 0, as -1 - 1
+    let x = (phase >> 16) as u16 as i16 as i32;
+
+    // Parabola through the peaks, then one refinement step weighted 0.225
+    let y = (4 * x * (Q15_ONE - x.abs())) >> 15;
+    let refined = y + ((7373 * (((y * y.abs()) >> 15) - y)) >> 15);
+
+    refined.clamp(-Q15_ONE, Q15_ONE)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{midi_note_millihertz, Oscillator, Waveform, Q15_ONE};
+
+    fn oscillator(millihertz: u32) -> Oscillator {
+        let mut oscillator = Oscillator::default();
+        oscillator.set_frequency(millihertz, 32_000);
+        oscillator.reset();
+        oscillator
+    }
+
+    #[test]
+    fn note_frequencies() {
+        assert_eq!(midi_note_millihertz(69), 440_000);
+        assert_eq!(midi_note_millihertz(60), 261_625);
+        assert_eq!(midi_note_millihertz(24), 32_703);
+        assert_eq!(midi_note_millihertz(120), 8_372_018);
+    }
+
+    #[test]
+    fn sine_peaks_near_full_scale() {
+        let mut oscillator = oscillator(1_000_000);
+        let mut peak = 0;
+
+        for _ in 0..32 {
+            peak = peak.max(oscillator.next(Waveform::Sine));
+        }
+
+        assert!(peak > Q15_ONE * 99 / 100 && peak <= Q15_ONE, "{}", peak);
+    }
+
+    #[test]
+    fn band_limited_saw_spreads_its_step() {
+        let mut oscillator = oscillator(midi_note_millihertz(96));
+        let mut previous = oscillator.next(Waveform::Saw);
+        let mut largest_step = 0;
+
+        for _ in 0..1000 {
+            let sample = oscillator.next(Waveform::Saw);
+            largest_step = largest_step.max((sample - previous).abs());
+            previous = sample;
+        }
+
+        // A naive saw drops the full 2.0 in one sample
+        assert!(largest_step < Q15_ONE * 3 / 2, "{}", largest_step);
+    }
+
+    #[test]
+    fn triangle_spans_full_scale_without_drifting() {
+        let mut oscillator = oscillator(midi_note_millihertz(57));
+        let (mut low, mut high) = (0, 0);
+
+        for sample in 0..32_000 {
+            let value = oscillator.next(Waveform::Triangle);
+
+            if sample > 31_000 {
+                low = low.min(value);
+                high = high.max(value);
+            }
+        }
+
+        assert!(low < -Q15_ONE * 9 / 10 && high > Q15_ONE * 9 / 10, "{} {}", low, high);
+        assert!((low + high).abs() < Q15_ONE / 20, "{} {}", low, high);
+    }
+}
@@ -0,0 +1,86 @@
+/// Semitone offsets from the root for each degree of a scale, repeating every octave.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Scale {
+    degrees: [u8; 12],
+    len: u8,
+}
+
+impl Scale {
+    pub const CHROMATIC: Scale = Scale::from_table([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11], 12);
+    pub const MAJOR: Scale = Scale::from_table([0, 2, 4, 5, 7, 9, 11, 0, 0, 0, 0, 0], 7);
+    /// Natural minor.
+    pub const MINOR: Scale = Scale::from_table([0, 2, 3, 5, 7, 8, 10, 0, 0, 0, 0, 0], 7);
+    /// Major pentatonic.
+    pub const PENTATONIC: Scale = Scale::from_table([0, 2, 4, 7, 9, 0, 0, 0, 0, 0, 0, 0], 5);
+
+    const fn from_table(degrees: [u8; 12], len: u8) -> Self {
+        Self { degrees, len }
+    }
+
+    /// A user defined scale from 1-12 strictly ascending semitone offsets below 12, starting from the root.
+    pub fn custom(degrees: &[u8]) -> Option<Scale> {
+        if degrees.is_empty() || degrees.len() > 12 || degrees[0] != 0 {
+            return None;
+        }
+
+        if degrees.windows(2).any(|pair| pair[0] >= pair[1]) || degrees[degrees.len() - 1] >= 12 {
+            return None;
+        }
+
+        let mut table = [0; 12];
+        table[..degrees.len()].copy_from_slice(degrees);
+
+        Some(Scale::from_table(table, degrees.len() as u8))
+    }
+
+    pub fn degrees(&self) -> &[u8] {
+        &self.degrees[..self.len as usize]
+    }
+
+    /// Semitones above the root of `degree`, which may run past the first octave.
+    pub fn semitones(&self, degree: u8) -> u8 {
+        (degree / self.len) * 12 + self.degrees[(degree % self.len) as usize]
+    }
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Scale::CHROMATIC
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Scale;
+
+    #[test]
+    fn chromatic_degree_is_semitone() {
+        for degree in 0..13 {
+            assert_eq!(Scale::CHROMATIC.semitones(degree), degree);
+        }
+    }
+
+    #[test]
+    fn major_wraps_into_next_octave() {
+        assert_eq!(Scale::MAJOR.semitones(2), 4);
+        assert_eq!(Scale::MAJOR.semitones(7), 12);
+        assert_eq!(Scale::MAJOR.semitones(12), 21);
+    }
+
+    #[test]
+    fn pentatonic_spans_two_octaves() {
+        assert_eq!(Scale::PENTATONIC.semitones(4), 9);
+        assert_eq!(Scale::PENTATONIC.semitones(12), 28);
+    }
+
+    #[test]
+    fn custom_scale_validation() {
+        assert_eq!(Scale::custom(&[0, 3, 7]).map(|scale| scale.semitones(4)), Some(15));
+        assert_eq!(Scale::custom(&[0, 2, 4, 5, 7, 9, 11]), Some(Scale::MAJOR));
+
+        assert_eq!(Scale::custom(&[]), None);
+        assert_eq!(Scale::custom(&[2, 4]), None);
+        assert_eq!(Scale::custom(&[0, 4, 4]), None);
+        assert_eq!(Scale::custom(&[0, 12]), None);
+    }
+}
@@ -0,0 +1,348 @@
+#[cfg(any(test, feature = "voice_events"))]
+use keyboard_matrix::EventQueue;
+
+use crate::{NoteSet, NoteState, SynthState, NUM_NOTES};
+
+/// Most voices the allocator can manage.
+pub const MAX_VOICES: usize = 8;
+
+pub const VOICE_EVENT_QUEUE_SIZE: usize = 16;
+
+/// Which voice gives way when a note needs one and all are in use.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StealPolicy {
+    /// The voice assigned longest ago.
+    Oldest,
+    /// The voice playing the lowest note.
+    Lowest,
+    /// The voice playing the highest note.
+    Highest,
+    /// The oldest voice already releasing, being the quietest, otherwise the oldest voice.
+    ReleasingFirst,
+}
+
+impl StealPolicy {
+    pub fn from_int(value: u8) -> Option<StealPolicy> {
+        match value {
+            0 => Some(StealPolicy::Oldest),
+            1 => Some(StealPolicy::Lowest),
+            2 => Some(StealPolicy::Highest),
+            3 => Some(StealPolicy::ReleasingFirst),
+            _ => None,
+        }
+    }
+
+    pub fn to_int(&self) -> u8 {
+        match self {
+            StealPolicy::Oldest => 0,
+            StealPolicy::Lowest => 1,
+            StealPolicy::Highest => 2,
+            StealPolicy::ReleasingFirst => 3,
+        }
+    }
+}
+
+/// Voice changes for a downstream synth to follow.  Notes are MIDI note numbers.
+///
+/// Only queued with the `voice_events` feature.  No register reads them back, so the firmware leaves it off
+/// and the allocator carries no queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoiceEvent {
+    /// A free voice, or one still releasing the same note, starts playing `note`.
+    Assign { voice: u8, note: u8 },
+    /// A voice is cut off from `stolen_note` to play `note`.
+    Steal { voice: u8, stolen_note: u8, note: u8 },
+    /// The note was released and the voice is fading out.
+    Release { voice: u8, note: u8 },
+    /// The voice finished releasing and is free.
+    Free { voice: u8, note: u8 },
+}
+
+// Only fills unused queue slots
+impl Default for VoiceEvent {
+    fn default() -> Self {
+        VoiceEvent::Free { voice: 0, note: 0 }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Voice {
+    note_index: Option<u8>,
+    releasing: bool,
+    assigned: u32,
+}
+
+/// Limits how many notes sound at once, assigning each note a voice and stealing voices when they run out.
+///
+/// A note that has its voice stolen stays silent until it is played again.
+pub struct VoiceAllocator {
+    pub enabled: bool,
+    pub policy: StealPolicy,
+
+    voice_count: usize,
+    voices: [Voice; MAX_VOICES],
+    stolen: NoteSet,
+    assignments: u32,
+    #[cfg(any(test, feature = "voice_events"))]
+    events: EventQueue<VoiceEvent, VOICE_EVENT_QUEUE_SIZE>,
+}
+
+impl Default for VoiceAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VoiceAllocator {
+    /// Disabled, with `MAX_VOICES` voices stealing the oldest.
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            policy: StealPolicy::Oldest,
+
+            voice_count: MAX_VOICES,
+            voices: [Voice::default(); MAX_VOICES],
+            stolen: NoteSet::EMPTY,
+            assignments: 0,
+            #[cfg(any(test, feature = "voice_events"))]
+            events: EventQueue::default(),
+        }
+    }
+
+    pub fn voice_count(&self) -> usize {
+        self.voice_count
+    }
+
+    /// Clamped to 1 - `MAX_VOICES`.  Notes on voices beyond the new count are stolen on the next update.
+    pub fn set_voice_count(&mut self, voice_count: usize) {
+        self.voice_count = voice_count.clamp(1, MAX_VOICES);
+    }
+
+    /// Host side only, needs the `voice_events` feature.
+    #[cfg(any(test, feature = "voice_events"))]
+    pub fn events(&mut self) -> &mut EventQueue<VoiceEvent, VOICE_EVENT_QUEUE_SIZE> {
+        &mut self.events
+    }
+
+    /// Note index the voice is playing or releasing.
+    pub fn voice_note(&self, voice: usize) -> Option<u8> {
+        self.voices.get(voice).and_then(|voice| voice.note_index)
+    }
+
+    /// Assigns voices to `sounding` notes given the current note states, returning the notes that have one.
+    pub fn update(&mut self, sounding: &NoteSet, note_index_state: &[NoteState; NUM_NOTES]) -> NoteSet {
+        self.stolen = self.stolen.intersection(*sounding);
+
+        for voice in self.voice_count..MAX_VOICES {
+            if let Some(note_index) = self.voices[voice].note_index {
+                if !self.voices[voice].releasing {
+                    self.stolen.insert(note_index as usize);
+                }
+
+                let note = SynthState::note_index_to_midi(note_index);
+
+                self.voices[voice].note_index = None;
+                self.push(VoiceEvent::Free { voice: voice as u8, note });
+            }
+        }
+
+        for voice in 0..self.voice_count {
+            let note_index = match self.voices[voice].note_index {
+                Some(note_index) => note_index,
+                None => continue,
+            };
+            let note = SynthState::note_index_to_midi(note_index);
+            let held = sounding.contains(note_index as usize) && !self.stolen.contains(note_index as usize);
+
+            if held && self.voices[voice].releasing {
+                self.voices[voice].releasing = false;
+                self.voices[voice].assigned = self.next_assignment();
+                self.push(VoiceEvent::Assign { voice: voice as u8, note });
+            } else if !held && !self.voices[voice].releasing {
+                self.voices[voice].releasing = true;
+                self.push(VoiceEvent::Release { voice: voice as u8, note });
+            } else if !held && note_index_state[note_index as usize] == NoteState::Off {
+                self.voices[voice].note_index = None;
+                self.push(VoiceEvent::Free { voice: voice as u8, note });
+            }
+        }
+
+        for note_index in sounding.difference(self.stolen).iter() {
+            let note_index = note_index as u8;
+
+            if self.voices[..self.voice_count].iter().any(|voice| voice.note_index == Some(note_index)) {
+                continue;
+            }
+
+            self.assign(note_index);
+        }
+
+        let mut voiced = NoteSet::EMPTY;
+        for voice in self.voices[..self.voice_count].iter().filter(|voice| !voice.releasing) {
+            if let Some(note_index) = voice.note_index {
+                voiced.insert(note_index as usize);
+            }
+        }
+
+        voiced
+    }
+
+    fn assign(&mut self, note_index: u8) {
+        let note = SynthState::note_index_to_midi(note_index);
+        let assigned = self.next_assignment();
+
+        let free_voice = self.voices[..self.voice_count].iter().position(|voice| voice.note_index.is_none());
+
+        if let Some(voice) = free_voice {
+            self.voices[voice] = Voice { note_index: Some(note_index), releasing: false, assigned };
+            self.push(VoiceEvent::Assign { voice: voice as u8, note });
+
+            return;
+        }
+
+        let voice = self.steal_candidate();
+        let stolen = self.voices[voice];
+
+        if let Some(stolen_index) = stolen.note_index {
+            if !stolen.releasing {
+                self.stolen.insert(stolen_index as usize);
+            }
+
+            self.push(VoiceEvent::Steal {
+                voice: voice as u8,
+                stolen_note: SynthState::note_index_to_midi(stolen_index),
+                note,
+            });
+        }
+
+        self.voices[voice] = Voice { note_index: Some(note_index), releasing: false, assigned };
+    }
+
+    fn steal_candidate(&self) -> usize {
+        let voices = self.voices[..self.voice_count].iter().enumerate();
+
+        let candidate = match self.policy {
+            StealPolicy::Oldest => voices.min_by_key(|(_, voice)| voice.assigned),
+            StealPolicy::Lowest => voices.min_by_key(|(_, voice)| voice.note_index),
+            StealPolicy::Highest => voices.max_by_key(|(_, voice)| voice.note_index),
+            StealPolicy::ReleasingFirst => voices.min_by_key(|(_, voice)| (!voice.releasing, voice.assigned)),
+        };
+
+        candidate.map_or(0, |(voice, _)| voice)
+    }
+
+    fn next_assignment(&mut self) -> u32 {
+        self.assignments = self.assignments.wrapping_add(1);
+        self.assignments
+    }
+
+    #[cfg(any(test, feature = "voice_events"))]
+    fn push(&mut self, event: VoiceEvent) {
+        // A full queue counts the drop for the consumer to notice
+        self.events.push(event).ok();
+    }
+
+    #[cfg(not(any(test, feature = "voice_events")))]
+    fn push(&mut self, _event: VoiceEvent) {}
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{NoteSet, NoteState, StealPolicy, VoiceAllocator, VoiceEvent, NUM_NOTES};
+
+    fn notes(note_indices: &[usize]) -> NoteSet {
+        let mut notes = NoteSet::EMPTY;
+        for note_index in note_indices {
+            notes.insert(*note_index);
+        }
+        notes
+    }
+
+    fn allocator(voice_count: usize, policy: StealPolicy) -> VoiceAllocator {
+        let mut voices = VoiceAllocator::new();
+        voices.enabled = true;
+        voices.policy = policy;
+        voices.set_voice_count(voice_count);
+        voices
+    }
+
+    #[test]
+    fn assigns_free_voices_in_order() {
+        let mut voices = allocator(2, StealPolicy::Oldest);
+        let states = [NoteState::Off; NUM_NOTES];
+
+        let voiced = voices.update(&notes(&[36, 40]), &states);
+
+        assert!(voiced == notes(&[36, 40]));
+        assert_eq!(voices.events().pop(), Some(VoiceEvent::Assign { voice: 0, note: 60 }));
+        assert_eq!(voices.events().pop(), Some(VoiceEvent::Assign { voice: 1, note: 64 }));
+        assert_eq!(voices.events().pop(), None);
+    }
+
+    #[test]
+    fn steals_oldest_and_keeps_stolen_note_silent() {
+        let mut voices = allocator(2, StealPolicy::Oldest);
+        let states = [NoteState::Off; NUM_NOTES];
+
+        voices.update(&notes(&[36]), &states);
+        voices.update(&notes(&[36, 40]), &states);
+        let voiced = voices.update(&notes(&[36, 40, 43]), &states);
+
+        assert!(voiced == notes(&[40, 43]));
+        assert_eq!(voices.events().len(), 3);
+        voices.events().pop();
+        voices.events().pop();
+        assert_eq!(voices.events().pop(), Some(VoiceEvent::Steal { voice: 0, stolen_note: 60, note: 67 }));
+
+        // Still held but stolen, so it does not steal back
+        assert!(voices.update(&notes(&[36, 40, 43]), &states) == notes(&[40, 43]));
+        assert!(voices.events().is_empty());
+    }
+
+    #[test]
+    fn lowest_and_highest_policies() {
+        let states = [NoteState::Off; NUM_NOTES];
+
+        let mut voices = allocator(2, StealPolicy::Lowest);
+        voices.update(&notes(&[40, 36]), &states);
+        assert!(voices.update(&notes(&[36, 40, 43]), &states) == notes(&[40, 43]));
+
+        let mut voices = allocator(2, StealPolicy::Highest);
+        voices.update(&notes(&[40, 36]), &states);
+        assert!(voices.update(&notes(&[36, 40, 43]), &states) == notes(&[36, 43]));
+    }
+
+    #[test]
+    fn releasing_voice_is_stolen_first_and_freed_when_off() {
+        let mut voices = allocator(2, StealPolicy::ReleasingFirst);
+        let mut states = [NoteState::Off; NUM_NOTES];
+
+        voices.update(&notes(&[36]), &states);
+        voices.update(&notes(&[36, 40]), &states);
+        states[40] = NoteState::Release;
+        voices.update(&notes(&[36]), &states);
+
+        while voices.events().pop() != Some(VoiceEvent::Release { voice: 1, note: 64 }) {}
+
+        assert!(voices.update(&notes(&[36, 43]), &states) == notes(&[36, 43]));
+        assert_eq!(voices.events().pop(), Some(VoiceEvent::Steal { voice: 1, stolen_note: 64, note: 67 }));
+
+        states[43] = NoteState::Off;
+        voices.update(&notes(&[36]), &states);
+        voices.update(&notes(&[36]), &states);
+        assert_eq!(voices.events().pop(), Some(VoiceEvent::Release { voice: 1, note: 67 }));
+        assert_eq!(voices.events().pop(), Some(VoiceEvent::Free { voice: 1, note: 67 }));
+        assert_eq!(voices.voice_note(1), None);
+    }
+
+    #[test]
+    fn reducing_voice_count_frees_extra_voices() {
+        let mut voices = allocator(3, StealPolicy::Oldest);
+        let states = [NoteState::Off; NUM_NOTES];
+
+        voices.update(&notes(&[36, 40, 43]), &states);
+        voices.set_voice_count(2);
+
+        assert!(voices.update(&notes(&[36, 40, 43]), &states) == notes(&[36, 40]));
+    }
+}